@@ -26,77 +26,1023 @@
 //! never get replaced.
 
 extern crate bootstrap;
+extern crate filetime;
+extern crate rustc_serialize;
+#[cfg(unix)]
+extern crate libc;
+#[cfg(windows)]
+extern crate kernel32;
+#[cfg(windows)]
+extern crate winapi;
 
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::env;
-use std::ffi::OsString;
-use std::path::PathBuf;
-use std::process::Command;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(unix)]
+use std::sync::atomic::{AtomicIsize, ATOMIC_ISIZE_INIT, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use filetime::FileTime;
+use rustc_serialize::json::{Json, ToJson};
+
+macro_rules! t {
+    ($e:expr) => (match $e {
+        Ok(e) => e,
+        Err(e) => panic!("{} failed with {}", stringify!($e), e),
+    })
+}
 
 fn main() {
     let args = env::args_os().skip(1).collect::<Vec<_>>();
+    let env = Env::new();
+    let cmd = configure(&args, &env);
+    for warning in cmd.warnings.iter() {
+        let _ = writeln!(io::stderr(), "warning: {}", warning);
+    }
+    for note in cmd.notes.iter() {
+        let _ = writeln!(io::stderr(), "note: {}", note);
+    }
+
+    // Actually run the compiler!
+    std::process::exit(run(&cmd))
+}
+
+/// A snapshot of the environment variables this shim was invoked with.
+///
+/// All configuration is read through this rather than `std::env` directly so
+/// the logic in `configure` can be exercised with a synthetic environment.
+struct Env {
+    vars: HashMap<String, OsString>,
+    /// Every variable we've looked at, whether or not it was set.
+    consumed: RefCell<BTreeSet<String>>,
+}
+
+impl Env {
+    fn new() -> Env {
+        Env::with_vars(env::vars_os().filter_map(|(k, v)| {
+            k.into_string().ok().map(|k| (k, v))
+        }).collect())
+    }
+
+    fn with_vars(vars: HashMap<String, OsString>) -> Env {
+        Env {
+            vars: vars,
+            consumed: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    fn var_os(&self, key: &str) -> Option<OsString> {
+        self.consumed.borrow_mut().insert(key.to_string());
+        self.vars.get(key).cloned()
+    }
+
+    /// Lists every variable we've looked at so far, one per line, as
+    /// `KEY=value` if it was set and just `KEY` if it wasn't.
+    fn consumed(&self) -> String {
+        let mut s = String::new();
+        for key in self.consumed.borrow().iter() {
+            match self.vars.get(key) {
+                Some(val) => s.push_str(&format!("{}={}\n", key,
+                                                 val.to_string_lossy())),
+                None => s.push_str(&format!("{}\n", key)),
+            }
+        }
+        return s
+    }
+
+    fn var(&self, key: &str) -> Option<String> {
+        self.var_os(key).and_then(|v| v.into_string().ok())
+    }
+
+    /// Returns the entries of the `sep`-separated list in `key`, skipping any
+    /// which are empty.
+    fn list(&self, key: &str, sep: char) -> Vec<String> {
+        match self.var(key) {
+            Some(s) => s.split(sep).map(|s| s.trim())
+                        .filter(|s| !s.is_empty())
+                        .map(|s| s.to_string())
+                        .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the numeric option `key`, complaining loudly if it's been set
+    /// to something that isn't a number.
+    fn number(&self, key: &str) -> Option<u64> {
+        self.var(key).map(|s| {
+            match s.parse() {
+                Ok(n) => n,
+                Err(..) => panic!("{} must be a number, not `{}`", key, s),
+            }
+        })
+    }
+
+    /// Returns the option `key`, complaining loudly if it's been set to
+    /// anything other than one of `choices`.
+    fn choice(&self, key: &str, choices: &[&str]) -> Option<String> {
+        self.var(key).map(|s| {
+            if !choices.contains(&&s[..]) {
+                let choices = choices.iter().map(|c| format!("`{}`", c))
+                                     .collect::<Vec<_>>();
+                panic!("invalid {} `{}`, expected one of {}", key, s,
+                       choices.join(", "));
+            }
+            s
+        })
+    }
+
+    /// Returns whether the boolean option `key` is enabled, which is the case
+    /// if it's set to anything other than an empty string, `0`, or `false`.
+    fn flag(&self, key: &str) -> bool {
+        match self.var(key) {
+            Some(s) => !s.is_empty() && s != "0" && s != "false",
+            None => false,
+        }
+    }
+}
+
+/// A fully configured invocation of the real compiler.
+///
+/// The arguments Cargo passed us are kept apart from the flags this shim adds
+/// on top of them, and the two are concatenated when the command is built.
+struct Invocation {
+    rustc: OsString,
+    stage: String,
+    target: Option<String>,
+    krate: Option<String>,
+    args: Vec<OsString>,
+    added: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+    /// Things worth telling the user about how we've been configured.
+    notes: Vec<String>,
+    /// Things that look wrong, but not wrong enough to stop the build.
+    warnings: Vec<String>,
+    /// File to record the optimization fuel consumed by this crate in.
+    fuel_log: Option<PathBuf>,
+    /// File to record statistics about this crate's drop glue in.
+    drop_glue_log: Option<PathBuf>,
+    /// File to append SARIF results for the compiler's diagnostics to.
+    sarif: Option<PathBuf>,
+    /// Whether Cargo itself asked for JSON diagnostics, in which case they're
+    /// passed through as-is rather than rendered.
+    forward_json: bool,
+    /// Whether we asked for JSON diagnostics ourselves, as Cargo didn't.
+    json_forced: bool,
+    /// Whether to hold back diagnostics until the compiler's done so each
+    /// distinct one is only emitted once.
+    dedup_diagnostics: bool,
+    /// File to write the compiler's JSON diagnostics to while their rendered
+    /// form goes to stderr.
+    json_out: Option<PathBuf>,
+    /// `objcopy` and the arguments to run it with on the final artifact.
+    objcopy: Option<(OsString, Vec<String>)>,
+    /// Whether to compile a second time to check the output is reproducible.
+    reproducible_check: bool,
+    /// File to merge this crate's dep-info into.
+    merged_dep_info: Option<PathBuf>,
+    /// How long to give the compiler before killing it.
+    timeout: Option<Duration>,
+    /// How long the compiler can take before we warn that it's being slow.
+    slow_compile: Option<Duration>,
+    /// File to record how the compiler exited in.
+    exit_status_file: Option<PathBuf>,
+    /// File to append a JSON summary of this invocation to.
+    stats_json: Option<PathBuf>,
+    /// File to append the SHA-256 hash of the artifact to.
+    hash_manifest: Option<PathBuf>,
+    /// File to copy everything the compiler prints into.
+    combined_log: Option<PathBuf>,
+    /// Directory to save what's needed to reproduce an ICE in.
+    ice_repro_dir: Option<PathBuf>,
+}
+
+impl Invocation {
+    fn new(rustc: OsString, stage: &str, target: Option<&str>,
+           args: &[OsString]) -> Invocation {
+        Invocation {
+            rustc: rustc,
+            stage: stage.to_string(),
+            target: target.map(|s| s.to_string()),
+            krate: arg_value_os(args, "--crate-name")
+                       .map(|k| k.to_string_lossy().into_owned()),
+            args: args.to_vec(),
+            added: Vec::new(),
+            env: Vec::new(),
+            notes: Vec::new(),
+            warnings: Vec::new(),
+            fuel_log: None,
+            drop_glue_log: None,
+            sarif: None,
+            forward_json: false,
+            json_forced: false,
+            dedup_diagnostics: false,
+            json_out: None,
+            objcopy: None,
+            reproducible_check: false,
+            merged_dep_info: None,
+            timeout: None,
+            slow_compile: None,
+            exit_status_file: None,
+            stats_json: None,
+            hash_manifest: None,
+            combined_log: None,
+            ice_repro_dir: None,
+        }
+    }
+
+    fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Invocation {
+        self.added.push(arg.as_ref().to_owned());
+        self
+    }
+
+    fn args<S: AsRef<OsStr>>(&mut self, args: &[S]) -> &mut Invocation {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    fn env<K, V>(&mut self, key: K, val: V) -> &mut Invocation
+        where K: AsRef<OsStr>, V: AsRef<OsStr>
+    {
+        self.env.push((key.as_ref().to_owned(), val.as_ref().to_owned()));
+        self
+    }
+
+    /// Returns the value following the first `flag` Cargo passed us, lossily
+    /// converted if it isn't valid UTF-8.
+    fn arg_value(&self, flag: &str) -> Option<Cow<str>> {
+        arg_value_os(&self.args, flag).map(|v| v.to_string_lossy())
+    }
+
+    /// Returns the path following the first `flag` Cargo passed us.
+    fn arg_path(&self, flag: &str) -> Option<PathBuf> {
+        arg_value_os(&self.args, flag).map(PathBuf::from)
+    }
+
+    /// Returns the name of the crate being compiled, if Cargo told us.
+    fn crate_name(&self) -> Option<&str> {
+        self.krate.as_ref().map(|s| &**s)
+    }
+
+    /// Returns the path of the dep-info file the compiler will write out.
+    fn dep_info(&self) -> Option<PathBuf> {
+        let dir = match self.arg_path("--out-dir") {
+            Some(dir) => dir,
+            None => return None,
+        };
+        let extra = self.codegen_opt("extra-filename").unwrap_or(String::new());
+        self.crate_name().map(|krate| dir.join(format!("{}{}.d", krate, extra)))
+    }
+
+    /// Returns the path of the primary artifact the compiler will produce,
+    /// named the same way the compiler itself names it.
+    fn artifact(&self) -> Option<PathBuf> {
+        if let Some(o) = self.arg_path("-o") {
+            return Some(o)
+        }
+        let krate = match self.crate_name() {
+            Some(krate) => krate,
+            None => return None,
+        };
+        let dir = self.arg_path("--out-dir").unwrap_or(PathBuf::new());
+        let extra = self.codegen_opt("extra-filename").unwrap_or(String::new());
+        // Build scripts are compiled for the host, which is what we are.
+        let (windows, apple, msvc) = match self.target {
+            Some(ref t) => (t.contains("windows"), t.contains("apple"),
+                            t.contains("msvc")),
+            None => (cfg!(windows), cfg!(target_os = "macos"),
+                     cfg!(target_env = "msvc")),
+        };
+        let crate_type = self.arg_value("--crate-type");
+        let name = match crate_type.as_ref().map_or("bin", |s| &**s) {
+            "lib" | "rlib" => format!("lib{}{}.rlib", krate, extra),
+            "dylib" | "cdylib" | "proc-macro" => {
+                if windows {
+                    format!("{}{}.dll", krate, extra)
+                } else if apple {
+                    format!("lib{}{}.dylib", krate, extra)
+                } else {
+                    format!("lib{}{}.so", krate, extra)
+                }
+            }
+            "staticlib" => {
+                if msvc {
+                    format!("{}{}.lib", krate, extra)
+                } else {
+                    format!("lib{}{}.a", krate, extra)
+                }
+            }
+            _ => {
+                if windows {
+                    format!("{}{}.exe", krate, extra)
+                } else {
+                    format!("{}{}", krate, extra)
+                }
+            }
+        };
+        Some(dir.join(name))
+    }
+
+    /// Returns the value of the codegen option `name` as it'll be seen by the
+    /// compiler, taking into account both Cargo's arguments and our own.
+    fn codegen_opt(&self, name: &str) -> Option<String> {
+        codegen_opt(self.args.iter().chain(self.added.iter()), name)
+    }
+
+    /// Like `codegen_opt`, but for `-Z` debugging options.
+    fn debugging_opt(&self, name: &str) -> Option<String> {
+        debugging_opt(self.args.iter().chain(self.added.iter()), name)
+    }
+
+    fn command(&self) -> Command {
+        self.command_with(&self.args)
+    }
+
+    /// Like `command`, but with `args` in place of the ones Cargo passed us.
+    fn command_with(&self, args: &[OsString]) -> Command {
+        let mut cmd = Command::new(&self.rustc);
+        cmd.args(args).args(&self.added);
+        for &(ref k, ref v) in self.env.iter() {
+            cmd.env(k, v);
+        }
+        return cmd
+    }
+}
+
+/// Works out which compiler to run for the arguments Cargo gave us, and with
+/// which extra flags and environment variables.
+fn configure(args: &[OsString], env: &Env) -> Invocation {
+    // Arguments which aren't valid UTF-8 are passed through untouched, but
+    // where we need to understand them we can only go by a lossy conversion,
+    // so this can be made an error instead.
+    let non_utf8 = non_utf8_args(args);
+    if env.flag("RUSTC_STRICT_ARG_ENCODING") {
+        if let Some(arg) = non_utf8.first() {
+            panic!("{} isn't valid UTF-8, which RUSTC_STRICT_ARG_ENCODING \
+                    requires", arg);
+        }
+    }
+
     // Detect whether or not we're a build script depending on whether --target
     // is passed (a bit janky...)
-    let target = args.windows(2).find(|w| &*w[0] == "--target")
-                                .and_then(|w| w[1].to_str());
+    //
+    // The target is matched against as a string, but passed along to anything
+    // else, e.g. as part of a path, as we were given it.
+    let target_os = arg_value_os(args, "--target");
+    let target = target_os.map(|t| t.to_string_lossy());
+    let target = target.as_ref().map(|t| &**t);
 
     // Build scripts always use the snapshot compiler which is guaranteed to be
     // able to produce an executable, whereas intermediate compilers may not
     // have the standard library built yet and may not be able to produce an
     // executable. Otherwise we just use the standard compiler we're
     // bootstrapping with.
-    let rustc = if target.is_none() {
-        env::var_os("RUSTC_SNAPSHOT").unwrap()
+    //
+    // A few target crates may also need the snapshot while they use features
+    // the in-tree compiler has yet to stabilize, so can be forced onto it.
+    let krate = arg_value_os(args, "--crate-name").map(|k| k.to_string_lossy());
+    let stage0_only = env.list("RUSTC_STAGE0_ONLY_CRATES", ',');
+    let snapshot = target.is_none() || krate.as_ref().map_or(false, |k| {
+        stage0_only.iter().any(|c| *c == **k)
+    });
+    let rustc = if snapshot {
+        env.var_os("RUSTC_SNAPSHOT").unwrap()
     } else {
-        env::var_os("RUSTC_REAL").unwrap()
+        env.var_os("RUSTC_REAL").unwrap()
     };
 
-    let mut cmd = Command::new(rustc);
-    cmd.args(&args)
-       .arg("--cfg").arg(format!("stage{}", env::var("RUSTC_STAGE").unwrap()));
+    let stage = env.var("RUSTC_STAGE").unwrap();
+    let mut cmd = Invocation::new(rustc, &stage, target, args);
+    for arg in non_utf8 {
+        cmd.warnings.push(format!("{} isn't valid UTF-8", arg));
+    }
+
+    // The stage cfgs can be left out entirely, which can help when debugging
+    // a crate that wasn't written with the bootstrap in mind.
+    if !env.flag("RUSTC_NO_STAGE_CFG") {
+        cmd.arg("--cfg").arg(format!("stage{}", stage));
+
+        // While migrating code between stages it can help to compile it as
+        // though it's also the next stage up.
+        match env.var("RUSTC_EXTRA_STAGE_CFG") {
+            Some(ref s) if s == "next" => {
+                let next = match stage.parse::<u32>() {
+                    Ok(n) => n + 1,
+                    Err(..) => panic!("RUSTC_STAGE `{}` isn't a number", stage),
+                };
+                cmd.arg("--cfg").arg(format!("stage{}", next));
+            }
+            Some(s) => panic!("invalid RUSTC_EXTRA_STAGE_CFG `{}`, expected \
+                               `next`", s),
+            None => {}
+        }
+    }
+
+    // Some configurations end up sharing an output directory across stages,
+    // so optionally give each stage its own to keep artifacts from clobbering
+    // one another.
+    if env.flag("RUSTC_OUT_DIR_PER_STAGE") {
+        per_stage_out_dir(&mut cmd.args, &format!("stage{}", stage));
+    }
+
+    // Incremental compilation tends to just slow down CI, where the caches
+    // are cold, so it can be turned off whatever Cargo says.
+    if env.flag("RUSTC_DISABLE_INCREMENTAL") {
+        cmd.args = strip_incremental(&cmd.args);
+    }
 
-    if target.is_none() {
-        // Build scripts are always built with the snapshot compiler, so we need
-        // to be sure to set up the right path information for the OS dynamic
-        // linker to find the libraries in question.
-        if let Some(p) = env::var_os("RUSTC_SNAPSHOT_LIBDIR") {
+    // Build scripts are always built with the snapshot compiler, so we need
+    // to be sure to set up the right path information for the OS dynamic
+    // linker to find the libraries in question.
+    if snapshot {
+        if let Some(p) = env.var_os("RUSTC_SNAPSHOT_LIBDIR") {
             let mut path = bootstrap::dylib_path();
             path.insert(0, PathBuf::from(p));
             cmd.env(bootstrap::dylib_path_var(), env::join_paths(path).unwrap());
         }
-    } else {
-        cmd.arg("--sysroot").arg(env::var_os("RUSTC_SYSROOT").unwrap());
+    }
+
+    // Flags from the environment which, unlike the rest of what we add, are
+    // meant to add to Cargo's rather than override them.
+    let mut user_flags = Vec::new();
+
+    if target.is_some() {
+        // Target crates forced onto the snapshot compiler have to be built
+        // against its own sysroot, which it finds for itself, rather than the
+        // one for this stage's compiler.
+        if !snapshot {
+            cmd.arg("--sysroot").arg(env.var_os("RUSTC_SYSROOT").unwrap());
+        }
+
+        // Overlay sysroots, e.g. with a patched std, are searched after the
+        // primary one by pointing at their target libraries directly.
+        if let (Some(extra), Some(target), Some(target_os)) =
+               (env.var_os("RUSTC_EXTRA_SYSROOT"), target, target_os) {
+            for sysroot in env::split_paths(&extra) {
+                let lib = sysroot.join("lib/rustlib").join(target_os).join("lib");
+                if !lib.is_dir() {
+                    panic!("RUSTC_EXTRA_SYSROOT `{}` has no libraries for \
+                            `{}` at {}", sysroot.display(), target,
+                           lib.display());
+                }
+                let mut dir = OsString::from("dependency=");
+                dir.push(&lib);
+                cmd.arg("-L").arg(&dir);
+            }
+        }
 
         // When we build Rust dylibs they're all intended for intermediate
         // usage, so make sure we pass the -Cprefer-dynamic flag instead of
         // linking all deps statically into the dylib.
         cmd.arg("-Cprefer-dynamic");
 
-        if let Some(s) = env::var_os("MUSL_ROOT") {
+        if let Some(s) = env.var_os("MUSL_ROOT") {
             let mut root = OsString::from("native=");
             root.push(&s);
             root.push("/lib");
             cmd.arg("-L").arg(&root);
         }
-        if let Ok(s) = env::var("RUSTC_FLAGS") {
+        if target.map_or(false, |t| t.contains("windows-gnu")) {
+            if let Some(s) = env.var_os("RUSTC_MINGW_CRT_DIR") {
+                let mut dir = OsString::from("native=");
+                dir.push(&s);
+                cmd.arg("-L").arg(&dir);
+            }
+        }
+        // Native libraries are passed through verbatim so the modifier syntax
+        // (`static:+whole-archive=foo` and friends) can be used.
+        for lib in env.list("RUSTC_NATIVE_LIBS", '\n') {
+            cmd.arg("-l").arg(lib);
+        }
+        // System libraries can be found with pkg-config. Its output is the
+        // same for every crate in a build, so it's cached in a stamp file in
+        // Cargo's output directory rather than asked for every time.
+        let pkgs = env.list("RUSTC_PKG_CONFIG_LIBS", ' ');
+        if !pkgs.is_empty() {
+            let out_dir = cmd.arg_path("--out-dir");
+            let libs = pkg_config_libs(env, &pkgs, out_dir.as_ref());
+            for flag in libs.split_whitespace() {
+                if flag.starts_with("-l") {
+                    cmd.arg("-l").arg(&flag[2..]);
+                } else if flag.starts_with("-L") {
+                    cmd.arg("-L").arg(format!("native={}", &flag[2..]));
+                } else {
+                    cmd.arg("-C").arg(format!("link-arg={}", flag));
+                }
+            }
+        }
+        let start = cmd.added.len();
+        if let Some(s) = env.var("RUSTC_FLAGS") {
             cmd.args(&s.split(" ").filter(|s| !s.is_empty()).collect::<Vec<_>>());
         }
+        // Flags can also be tuned per Cargo profile, which we have to work out
+        // for ourselves from what Cargo's asked for.
+        let key = match profile(args) {
+            "dev" => "RUSTC_FLAGS_DEV",
+            _ => "RUSTC_FLAGS_RELEASE",
+        };
+        cmd.args(&env.list(key, ' '));
+        user_flags = cmd.added[start..].to_vec();
+    }
+
+    // Diagnostics can be collected into a SARIF log, with a run for each
+    // invocation, or collapsed when the same one is repeated for many
+    // instantiations of a generic, for both of which we need them in JSON
+    // form. Cargo may have already asked for that, in which case it gets the
+    // JSON back, otherwise we render them as usual.
+    let sarif = env.var_os("RUSTC_SARIF_OUT");
+    let dedup = env.flag("RUSTC_DEDUP_DIAGNOSTICS");
+    if sarif.is_some() || dedup {
+        let key = if sarif.is_some() {
+            "RUSTC_SARIF_OUT"
+        } else {
+            "RUSTC_DEDUP_DIAGNOSTICS"
+        };
+        match cmd.arg_value("--error-format").map(|s| s.into_owned()) {
+            Some(ref format) if format == "json" => cmd.forward_json = true,
+            Some(format) => panic!("{} needs JSON diagnostics, but Cargo \
+                                    asked for `{}`", key, format),
+            None => {
+                cmd.arg("--error-format").arg("json");
+                cmd.json_forced = true;
+            }
+        }
+        cmd.sarif = sarif.map(PathBuf::from);
+        cmd.dedup_diagnostics = dedup;
+    }
+
+    // When JSON diagnostics are wanted for tooling, but someone's also
+    // watching the console, the compiler can render each one for us as well.
+    // The rendering goes to stderr and the JSON to a file by the crate's
+    // other outputs.
+    if env.flag("RUSTC_JSON_WITH_RENDERED") {
+        let cargo_json = cmd.arg_value("--error-format").map_or(false, |f| {
+            f == "json"
+        });
+        let json = cargo_json || cmd.json_forced;
+        let out_dir = cmd.arg_path("--out-dir");
+        match (json, out_dir, cmd.crate_name()) {
+            (true, Some(dir), Some(krate)) => {
+                let extra = cmd.codegen_opt("extra-filename")
+                               .unwrap_or(String::new());
+                let out = dir.join(format!("{}{}.diagnostics.json", krate, extra));
+                cmd.json_out = Some(out);
+            }
+            (true, ..) => {
+                cmd.notes.push("ignoring RUSTC_JSON_WITH_RENDERED without an \
+                                output directory and crate name".to_string());
+            }
+            (false, ..) => {
+                cmd.notes.push("ignoring RUSTC_JSON_WITH_RENDERED without \
+                                JSON diagnostics".to_string());
+            }
+        }
+        // Cargo still gets the JSON if it asked for it, and the rendering
+        // otherwise.
+        if cmd.json_out.is_some() {
+            cmd.arg("--json=diagnostic-rendered-ansi");
+            cmd.forward_json = cargo_json;
+        }
+    }
+
+    // Some artifacts need a little post-processing with objcopy, such as
+    // prefixing symbols or stripping sections, once they've been produced.
+    if let (Some(objcopy), Some(s)) = (env.var_os("RUSTC_OBJCOPY"),
+                                       env.var("RUSTC_OBJCOPY_ARGS")) {
+        let args = s.split(" ").filter(|s| !s.is_empty())
+                    .map(|s| s.to_string()).collect();
+        cmd.objcopy = Some((objcopy, args));
+    }
+
+    cmd.exit_status_file = env.var_os("RUSTC_EXIT_STATUS_FILE").map(PathBuf::from);
+    cmd.stats_json = env.var_os("RUSTC_STATS_JSON").map(PathBuf::from);
+
+    cmd.merged_dep_info = env.var_os("RUSTC_MERGED_DEP_INFO").map(PathBuf::from);
+    cmd.hash_manifest = env.var_os("RUSTC_HASH_MANIFEST").map(PathBuf::from);
+    cmd.ice_repro_dir = env.var_os("RUSTC_ICE_REPRO_DIR").map(PathBuf::from);
+
+    // Everything the compiler prints can be kept for a post-mortem, as well
+    // as being passed on as usual.
+    if let Some(dir) = env.var_os("RUSTC_COMBINED_LOG_DIR") {
+        t!(fs::create_dir_all(&dir));
+        let krate = cmd.crate_name().unwrap_or("unknown").to_string();
+        cmd.combined_log = Some(Path::new(&dir).join(format!("{}.log", krate)));
+    }
+
+    // Occasionally a crate sends the compiler off into the weeds, so give up
+    // on it after a while rather than hanging the whole build.
+    match env.number("RUSTC_BUILD_TIMEOUT") {
+        Some(0) | None => {}
+        Some(secs) => {
+            cmd.timeout = Some(Duration::from_secs(secs));
+            // On Unix the compiler's output then goes through us (see
+            // `ProcessTree`), so it'd lose its colours unless we ask for them.
+            if cfg!(unix) && cmd.arg_value("--color").is_none() && stderr_is_tty() {
+                cmd.arg("--color").arg("always");
+            }
+        }
+    }
+    match env.number("RUSTC_SLOW_COMPILE_SECS") {
+        Some(0) | None => {}
+        Some(secs) => cmd.slow_compile = Some(Duration::from_secs(secs)),
+    }
+
+    // Checking reproducibility means compiling everything twice, so this can
+    // be limited to just the crates we're interested in.
+    if env.flag("RUSTC_REPRODUCIBLE_CHECK") {
+        let crates = env.list("RUSTC_REPRODUCIBLE_CRATES", ',');
+        let krate = cmd.crate_name().unwrap_or("").to_string();
+        cmd.reproducible_check = crates.is_empty() || crates.contains(&krate);
     }
 
     // Set various options from config.toml to configure how we're building
     // code.
-    if let Some(target) = target {
-        if env::var("RUSTC_DEBUGINFO") == Ok("true".to_string()) {
+    if let (Some(target), Some(target_os)) = (target, target_os) {
+        if env.var("RUSTC_DEBUGINFO") == Some("true".to_string()) {
             cmd.arg("-g");
         }
-        let debug_assertions = match env::var("RUSTC_DEBUG_ASSERTIONS") {
-            Ok(s) => if s == "true" {"y"} else {"n"},
-            Err(..) => "n",
+        // Debuginfo can also be turned on for just the crates being debugged,
+        // rather than bloating the whole build. Codegen options are
+        // last-one-wins, so this overrides debuginfo being turned off.
+        if let Some(krate) = cmd.crate_name().map(|s| s.to_string()) {
+            if env.list("RUSTC_DEBUGINFO_CRATES", ',').contains(&krate) {
+                let level = env.choice("RUSTC_DEBUGINFO_CRATES_LEVEL", &["1", "2"])
+                               .unwrap_or("2".to_string());
+                cmd.arg("-C").arg(format!("debuginfo={}", level));
+            }
+        }
+        let debug_assertions = match env.var("RUSTC_DEBUG_ASSERTIONS") {
+            Some(s) => if s == "true" {"y"} else {"n"},
+            None => "n",
         };
         cmd.arg("-C").arg(format!("debug-assertions={}", debug_assertions));
-        if let Ok(s) = env::var("RUSTC_CODEGEN_UNITS") {
+        // Lots of codegen units in a big crate can use a lot of memory, so
+        // optionally use fewer the bigger the crate looks, going by the size
+        // of its root source file. Any number we're given is still the most
+        // that'll be used, and without one we start from the compiler's own
+        // default, so we only ever pass fewer than it would use anyway.
+        if env.flag("RUSTC_ADAPTIVE_CGUS") {
+            let explicit = env.number("RUSTC_CODEGEN_UNITS");
+            let base = explicit.map_or(DEFAULT_CODEGEN_UNITS, |n| n as usize);
+            let threshold = env.number("RUSTC_ADAPTIVE_CGUS_THRESHOLD")
+                               .unwrap_or(256 * 1024);
+            let rs = Some(OsStr::new("rs"));
+            let size = args.iter().find(|a| Path::new(a).extension() == rs)
+                           .and_then(|src| fs::metadata(src).ok())
+                           .map_or(0, |m| m.len());
+            let cgus = scale_cgus(size, base, threshold);
+            if explicit.is_some() || cgus < DEFAULT_CODEGEN_UNITS {
+                cmd.arg("-C").arg(format!("codegen-units={}", cgus));
+            }
+        } else if let Some(s) = env.var("RUSTC_CODEGEN_UNITS") {
             cmd.arg("-C").arg(format!("codegen-units={}", s));
         }
 
+        // The test harness relies on unwinding to report failing tests, so if
+        // we're building with panic=abort each test needs to be run in its own
+        // process instead.
+        if env.flag("RUSTC_PANIC_ABORT_TESTS") &&
+           args.iter().any(|a| &**a == "--test") &&
+           cmd.codegen_opt("panic") == Some("abort".to_string()) {
+            cmd.arg("-Z").arg("panic-abort-tests");
+        }
+
+        let visibilities = ["default", "hidden", "protected"];
+        if let Some(s) = env.choice("RUSTC_DEFAULT_VISIBILITY", &visibilities) {
+            cmd.arg("-Z").arg(format!("default-visibility={}", s));
+        }
+        let modes = ["disabled", "trampolines", "aliases"];
+        if let Some(s) = env.choice("RUSTC_MERGE_FUNCTIONS", &modes) {
+            cmd.arg("-Z").arg(format!("merge-functions={}", s));
+        }
+
+        let wasm_link_args = env.list("RUSTC_WASM_LINK_ARGS", '\n');
+        if target.contains("wasm") {
+            for arg in wasm_link_args {
+                cmd.arg("-C").arg(format!("link-arg={}", arg));
+            }
+        } else if !wasm_link_args.is_empty() {
+            cmd.notes.push(format!("ignoring RUSTC_WASM_LINK_ARGS for \
+                                    non-wasm target `{}`", target));
+        }
+
+        if let Some(n) = env.number("RUSTC_MOVE_SIZE_LIMIT") {
+            cmd.arg("-Z").arg(format!("move-size-limit={}", n));
+        }
+
+        // Split debuginfo on apple targets doesn't always play well with lldb,
+        // so specific crates can be given plain DWARF. Codegen options are
+        // last-one-wins, so this overrides whatever the global setting is.
+        if target.contains("apple") {
+            let krate = cmd.crate_name().unwrap_or("").to_string();
+            if env.list("RUSTC_FORCE_DWARF_CRATES", ',').contains(&krate) {
+                cmd.arg("-C").arg("split-debuginfo=off");
+            }
+        }
+
+        // The same crate built for different configurations into different
+        // sysroots can end up with colliding symbol hashes. Every `-C metadata`
+        // is mixed into those hashes, so an extra one tells the builds apart.
+        if let Some(salt) = env.var("RUSTC_HASH_SALT") {
+            cmd.arg("-C").arg(format!("metadata={}", salt));
+        }
+
+        // Dependencies of std can be swapped out for patched versions, which
+        // is otherwise awkward to arrange with Cargo.
+        if cmd.crate_name().map_or(false, is_std_crate) {
+            for dep in env.list("RUSTC_SYSROOT_DEP_OVERRIDE", '\n') {
+                let mut parts = dep.splitn(2, '=');
+                let (name, path) = match (parts.next(), parts.next()) {
+                    (Some(name), Some(path)) if !name.is_empty() &&
+                                                !path.is_empty() => (name, path),
+                    _ => panic!("invalid RUSTC_SYSROOT_DEP_OVERRIDE entry `{}`, \
+                                 expected `name=path`", dep),
+                };
+                // A bare file name has an empty parent rather than none.
+                let dir = match Path::new(path).parent() {
+                    Some(dir) if dir != Path::new("") => dir,
+                    _ => Path::new("."),
+                };
+                cmd.arg("--extern").arg(format!("{}={}", name, path));
+                let mut search = OsString::from("dependency=");
+                search.push(dir);
+                cmd.arg("-L").arg(&search);
+            }
+        }
+
+        if env.var("RUSTC_INLINE_MIR").is_some() {
+            let inline = if env.flag("RUSTC_INLINE_MIR") {"yes"} else {"no"};
+            cmd.arg("-Z").arg(format!("inline-mir={}", inline));
+        }
+        if let Some(n) = env.number("RUSTC_INLINE_MIR_THRESHOLD") {
+            cmd.arg("-Z").arg(format!("inline-mir-threshold={}", n));
+        }
+
+        // Pointer provenance lints, to help along the strict provenance
+        // migration of the standard library. They're unstable, so without
+        // their feature they'd only be unknown lints, which is an error for
+        // crates denying warnings.
+        if env.flag("RUSTC_PROVENANCE_LINTS") &&
+           cmd.crate_name().map_or(false, is_std_crate) {
+            cmd.arg("-Z").arg("crate-attr=feature(strict_provenance_lints)");
+            cmd.arg("-W").arg("fuzzy_provenance_casts");
+            cmd.arg("-W").arg("lossy_provenance_casts");
+        }
+
+        if let Some(dir) = env.var_os("RUSTC_MONO_STATS_DIR") {
+            t!(fs::create_dir_all(&dir));
+            let mut flag = OsString::from("dump-mono-stats=");
+            flag.push(&dir);
+            cmd.arg("-Z").arg(flag);
+        }
+        let formats = ["markdown", "json"];
+        if let Some(s) = env.choice("RUSTC_MONO_STATS_FORMAT", &formats) {
+            cmd.arg("-Z").arg(format!("dump-mono-stats-format={}", s));
+        }
+
+        // Picking lld-link as the linker needs the flavor to match, which is
+        // easy to forget, so this sets both together.
+        if env.flag("RUSTC_WINDOWS_USE_LLD") {
+            if target.contains("windows-msvc") {
+                cmd.arg("-C").arg("linker=lld-link");
+                cmd.arg("-C").arg("linker-flavor=lld-link");
+            } else {
+                cmd.notes.push(format!("ignoring RUSTC_WINDOWS_USE_LLD for \
+                                        non-msvc target `{}`", target));
+            }
+        }
+
+        // Recovering from sanitizer errors only makes sense if a sanitizer is
+        // actually enabled, and the runtime options need to make it through to
+        // anything the compiler runs, like build scripts, as well.
+        if let Some(recover) = env.var("RUSTC_SANITIZER_RECOVER") {
+            if cmd.debugging_opt("sanitizer").is_none() {
+                panic!("RUSTC_SANITIZER_RECOVER is set but no sanitizer is \
+                        enabled with `-Z sanitizer`");
+            }
+            cmd.arg("-Z").arg(format!("sanitizer-recover={}", recover));
+            for key in ["ASAN_OPTIONS", "TSAN_OPTIONS"].iter() {
+                if let Some(val) = env.var_os(key) {
+                    cmd.env(key, val);
+                }
+            }
+        }
+
+        // Object files are normally thrown away, but for debugging LTO it
+        // helps to keep them around. Multiple `--emit` flags add up, so this
+        // leaves whatever Cargo asked for alone.
+        if let Some(dir) = env.var_os("RUSTC_EMIT_OBJ_DIR") {
+            t!(fs::create_dir_all(&dir));
+            let krate = cmd.crate_name().unwrap_or("out").to_string();
+            let extra = cmd.codegen_opt("extra-filename").unwrap_or(String::new());
+            let obj = Path::new(&dir).join(format!("{}{}.o", krate, extra));
+            let mut emit = OsString::from("--emit=obj=");
+            emit.push(&obj);
+            cmd.arg(emit);
+        }
+
+        // The linker can be wrapped in a script to do extra work around the
+        // link, in the same way this shim wraps the compiler. The wrapper is
+        // told which linker it's standing in for through `RUSTC_REAL_LINKER`.
+        if let Some(wrapper) = env.var_os("RUSTC_LINKER_WRAPPER") {
+            let linker = match cmd.codegen_opt("linker") {
+                Some(linker) => linker,
+                None if target.contains("msvc") => "link.exe".to_string(),
+                None => "cc".to_string(),
+            };
+            let mut flag = OsString::from("linker=");
+            flag.push(&wrapper);
+            cmd.arg("-C").arg(flag);
+            cmd.env("RUSTC_REAL_LINKER", linker);
+        }
+
+        // Overflow checks can be kept on in std regardless of the profile,
+        // without paying for them in the compiler. Codegen options are
+        // last-one-wins, so this overrides any global setting.
+        if env.flag("RUSTC_OVERFLOW_CHECKS_STD") &&
+           cmd.crate_name().map_or(false, is_std_crate) {
+            cmd.arg("-C").arg("overflow-checks=y");
+        }
+
+        // Likewise profilers want frame pointers in std, which the compiler
+        // crates can do without.
+        if env.flag("RUSTC_FRAME_POINTERS_STD_ONLY") &&
+           cmd.crate_name().map_or(false, is_std_crate) {
+            cmd.arg("-C").arg("force-frame-pointers=yes");
+        }
+
+        // Target features are passed along as given, but as an unknown one
+        // tends to be silently ignored they can optionally be checked against
+        // what the compiler knows about for this target first.
+        let features = env.list("RUSTC_TARGET_FEATURES", ',');
+        if !features.is_empty() {
+            if env.flag("RUSTC_VALIDATE_TARGET_FEATURES") {
+                let sysroot = PathBuf::from(env.var_os("RUSTC_SYSROOT").unwrap());
+                let known = target_features(&cmd.rustc, target_os, &sysroot);
+                for feature in features.iter() {
+                    let name = feature.trim_left_matches(|c| c == '+' || c == '-');
+                    if !known.iter().any(|k| k == name) {
+                        panic!("unknown target feature `{}` for `{}` in \
+                                RUSTC_TARGET_FEATURES", name, target);
+                    }
+                }
+            }
+            cmd.arg("-C").arg(format!("target-feature={}", features.join(",")));
+        }
+
+        // Stack usage analysis for embedded targets reads the
+        // `.stack_sizes` section, which only ELF objects get.
+        if env.flag("RUSTC_EMIT_STACK_SIZES") {
+            if is_elf(target) {
+                cmd.arg("-Z").arg("emit-stack-sizes");
+            } else {
+                cmd.notes.push(format!("ignoring RUSTC_EMIT_STACK_SIZES for \
+                                        non-ELF target `{}`", target));
+            }
+        }
+
+        // If std in the sysroot is older than the compiler using it then it
+        // probably wasn't rebuilt when it should have been.
+        if env.flag("RUSTC_DETECT_STALE_SYSROOT") {
+            let sysroot = PathBuf::from(env.var_os("RUSTC_SYSROOT").unwrap());
+            let lib = sysroot.join("lib/rustlib").join(target_os).join("lib");
+            let compiler = fs::metadata(env.var_os("RUSTC_REAL").unwrap())
+                              .map(|m| mtime(&m));
+            let libstd = fs::read_dir(&lib).map(|dir| {
+                dir.filter_map(|e| e.ok()).filter(|e| {
+                    e.file_name().to_str().map_or(false, |name| {
+                        name.starts_with("libstd-") && name.ends_with(".rlib")
+                    })
+                }).filter_map(|e| e.metadata().map(|m| mtime(&m)).ok())
+                  .collect::<Vec<_>>()
+            }).unwrap_or(Vec::new());
+            if let Ok(compiler) = compiler {
+                if is_stale(compiler, &libstd) {
+                    cmd.warnings.push(format!("std in {} is older than the \
+                                               compiler, it may be stale",
+                                              lib.display()));
+                }
+            }
+        }
+
+        // Large sets of cfgs are easier to keep in a file than the
+        // environment, one per line.
+        if let Some(path) = env.var_os("RUSTC_CFG_FILE") {
+            let cfgs = String::from_utf8(read_bytes(Path::new(&path))).unwrap();
+            for cfg in cfgs.lines().map(|l| l.trim()) {
+                if !cfg.is_empty() && !cfg.starts_with("#") {
+                    cmd.arg("--cfg").arg(cfg);
+                }
+            }
+        }
+
+        // Metadata can go in a `.rmeta` file of its own rather than being
+        // embedded in the rlib, for caches that want to fetch it separately.
+        // Dependents then need both files, so this needs a compiler new
+        // enough to know `-Z embed-metadata` (nightlies from 2025 on), which
+        // the snapshot compiler won't be.
+        if env.flag("RUSTC_SPLIT_METADATA") {
+            cmd.arg("-Z").arg("embed-metadata=no");
+            cmd.arg("--emit=metadata");
+        }
+
+        // Fields of a custom target spec can be tweaked without editing it,
+        // by handing the compiler a patched copy instead.
+        let overrides = env.list("RUSTC_TARGET_SPEC_OVERRIDES", '\n');
+        if !overrides.is_empty() && target.ends_with(".json") {
+            let spec = override_target_spec(Path::new(target_os), &overrides);
+            set_arg_value(&mut cmd.args, "--target", spec.as_os_str());
+        }
+
+        // Warnings are only worth denying in the crates we maintain, rather
+        // than those vendored from elsewhere.
+        if let Some(krate) = cmd.crate_name().map(|s| s.to_string()) {
+            if env.list("RUSTC_DENY_WARNINGS_CRATES", ',').contains(&krate) {
+                cmd.arg("-D").arg("warnings");
+            }
+        }
+
+        // Some caches want dep-info to cover everything, including the rlibs
+        // and other binaries a crate was built from, not just its sources.
+        // `--emit`s add up, so this keeps whatever else Cargo asked for.
+        if env.flag("RUSTC_FULL_DEP_INFO") {
+            cmd.arg("-Z").arg("binary-dep-depinfo");
+            cmd.arg("--emit=dep-info");
+        }
+
+        // Crate-level attributes can be injected for experiments, either into
+        // every crate or just one via e.g. `RUSTC_CRATE_ATTR_STD`.
+        let mut attrs = env.list("RUSTC_CRATE_ATTR", '\n');
+        if let Some(krate) = cmd.crate_name() {
+            let key = format!("RUSTC_CRATE_ATTR_{}", krate.to_uppercase());
+            attrs.extend(env.list(&key, '\n'));
+        }
+        for attr in attrs {
+            cmd.arg("-Z").arg(format!("crate-attr={}", attr));
+        }
+
+        // Optimization fuel is handy for bisecting a miscompilation down to a
+        // single optimization, and when doing so it's useful to know how much
+        // fuel each crate actually consumed.
+        if let Some(fuel) = env.var("RUSTC_FUEL") {
+            if let Some(krate) = cmd.crate_name().map(|s| s.to_string()) {
+                cmd.arg("-Z").arg(format!("fuel={}={}", krate, fuel));
+                if let Some(log) = env.var_os("RUSTC_FUEL_LOG") {
+                    cmd.arg("-Z").arg(format!("print-fuel={}", krate));
+                    cmd.fuel_log = Some(PathBuf::from(log));
+                }
+            }
+        }
+
+        // For code size analysis, have the compiler list everything it
+        // translates so we can count up how much of it is drop glue.
+        if let Some(log) = env.var_os("RUSTC_DROP_GLUE_STATS") {
+            cmd.arg("-Z").arg("print-trans-items=lazy");
+            cmd.drop_glue_log = Some(PathBuf::from(log));
+        }
+
+        // Target modifiers affect the ABI, so every crate for a target has to
+        // agree on them. The first set we see for a target is recorded in
+        // `$RUSTC_SYSROOT/target-modifiers-stage<N>-<target>.stamp` (see
+        // `target_stamp` for custom targets), and any later crate in the same
+        // build and stage that asks for a different set gets a warning.
+        if env.var_os("RUSTC_TARGET_MODIFIERS").is_some() {
+            let mut modifiers = env.list("RUSTC_TARGET_MODIFIERS", ',');
+            for modifier in modifiers.iter() {
+                cmd.arg("-Z").arg(modifier);
+            }
+            let sysroot = PathBuf::from(env.var_os("RUSTC_SYSROOT").unwrap());
+            let prefix = format!("target-modifiers-stage{}", cmd.stage);
+            let stamp = target_stamp(&sysroot, &prefix, target_os);
+            modifiers.sort();
+            let modifiers = modifiers.join(",");
+            if stamp.exists() {
+                let recorded = String::from_utf8(read_bytes(&stamp)).unwrap();
+                if recorded != modifiers {
+                    cmd.warnings.push(format!("target modifiers `{}` differ from \
+                                               the `{}` used by earlier crates \
+                                               for `{}`", modifiers, recorded,
+                                              target));
+                }
+            } else {
+                t!(fs::create_dir_all(&sysroot));
+                write_atomically(&stamp, modifiers.as_bytes());
+            }
+        }
+
+        // Arguments destined for the linker are collected here and passed
+        // along as one `-C link-args` bundle at the end.
+        let mut link_args = Vec::new();
         // Dealing with rpath here is a little special, so let's go into some
         // detail. First off, `-rpath` is a linker option on Unix platforms
         // which adds to the runtime dynamic loader path when looking for
@@ -123,7 +1069,7 @@ fn main() {
         // argument manually via `-C link-args=-Wl,-rpath,...`. Plus isn't it
         // fun to pass a flag to a tool to pass a flag to pass a flag to a tool
         // to change a flag in a binary?
-        if env::var("RUSTC_RPATH") == Ok("true".to_string()) {
+        if env.var("RUSTC_RPATH") == Some("true".to_string()) {
             let rpath = if target.contains("apple") {
                 Some("-Wl,-rpath,@loader_path/../lib")
             } else if !target.contains("windows") {
@@ -132,14 +1078,3318 @@ fn main() {
                 None
             };
             if let Some(rpath) = rpath {
-                cmd.arg("-C").arg(format!("link-args={}", rpath));
+                link_args.push(rpath.to_string());
+            }
+        }
+
+        // Section garbage collection can be forced on or off, which only
+        // means something to the linkers used for ELF targets.
+        if env.var("RUSTC_GC_SECTIONS").is_some() {
+            if is_elf(target) {
+                link_args.push(if env.flag("RUSTC_GC_SECTIONS") {
+                    "-Wl,--gc-sections".to_string()
+                } else {
+                    "-Wl,--no-gc-sections".to_string()
+                });
+            } else {
+                cmd.notes.push(format!("ignoring RUSTC_GC_SECTIONS for \
+                                        non-ELF target `{}`", target));
+            }
+        }
+
+        // ThinLTO redoes a lot of work from one build to the next unless the
+        // linker is given somewhere to cache it.
+        if let Some(dir) = env.var("RUSTC_THINLTO_CACHE_DIR") {
+            let thin = cmd.codegen_opt("lto") == Some("thin".to_string()) ||
+                       cmd.debugging_opt("thinlto") == Some("yes".to_string());
+            if !thin {
+                panic!("RUSTC_THINLTO_CACHE_DIR is set but ThinLTO isn't \
+                        enabled, pass `-C lto=thin` to enable it");
+            }
+            // `link-args` is split on whitespace, with no way of quoting.
+            if dir.chars().any(|c| c.is_whitespace()) {
+                panic!("RUSTC_THINLTO_CACHE_DIR `{}` contains whitespace, which \
+                        can't be passed through `-C link-args`", dir);
+            }
+            t!(fs::create_dir_all(&dir));
+            link_args.push(if target.contains("apple") {
+                format!("-Wl,-cache_path_lto,{}", dir)
+            } else if target.contains("msvc") {
+                format!("/lldltocache:{}", dir)
+            } else {
+                format!("-Wl,--thinlto-cache-dir={}", dir)
+            });
+        }
+
+        if !link_args.is_empty() {
+            // Duplicates can creep in as more things contribute link args,
+            // and some linkers warn about them, so optionally strip them.
+            if env.flag("RUSTC_LINK_ARGS_DEDUP") {
+                link_args = dedup_link_args(&link_args);
             }
+            cmd.arg("-C").arg(format!("link-args={}", link_args.join(" ")));
         }
     }
 
-    // Actually run the compiler!
-    std::process::exit(match cmd.status() {
-        Ok(s) => s.code().unwrap_or(1),
-        Err(e) => panic!("\n\nfailed to run {:?}: {}\n\n", cmd, e),
-    })
+    // Distributed builds may only have dependencies' rlibs locally once
+    // they've been fetched, which a resolver can do for us, telling us where
+    // it's put them.
+    if let Some(resolver) = env.var_os("RUSTC_EXTERN_RESOLVER") {
+        resolve_externs(&resolver, &mut cmd.args);
+    }
+
+    // Symlinks in the build directory otherwise leak into dep-info and
+    // diagnostics and make them differ from build to build.
+    if env.flag("RUSTC_CANONICALIZE_PATHS") {
+        canonicalize_paths(&mut cmd.args);
+        canonicalize_paths(&mut cmd.added);
+    }
+
+    // With so many places contributing flags it's easy to end up passing a
+    // single-valued one twice, at which point only the last one counts. The
+    // flags the shim adds itself often override Cargo's on purpose, e.g. a
+    // linker wrapper, so only Cargo's own and those from RUSTC_FLAGS count.
+    if env.flag("RUSTC_ABORT_ON_DUPLICATE_FLAGS") {
+        if let Some(flag) = duplicate_flag(cmd.args.iter().chain(&user_flags)) {
+            panic!("`{}` was passed to the compiler more than once", flag);
+        }
+    }
+
+    // With so many things contributing flags it can be hard to tell what we
+    // ended up adding, so optionally write that out, one argument per line
+    // after a header for the invocation. Each section is appended in one go
+    // so parallel invocations don't interleave.
+    if let Some(audit) = env.var_os("RUSTC_FLAGS_AUDIT") {
+        let mut section = format!("# crate={} target={} stage={}",
+                                  cmd.crate_name().unwrap_or("unknown"),
+                                  target.unwrap_or("host"), stage);
+        for arg in cmd.added.iter() {
+            section.push('\n');
+            section.push_str(&arg.to_string_lossy());
+        }
+        section.push('\n');
+        append_line(Path::new(&audit), &section);
+    }
+
+    // Cargo doesn't know about the environment variables that drive this
+    // shim either, so incremental state built under different ones can be
+    // thrown away for a clean recompile. Like the sidecar below, this comes
+    // late so the hash covers nearly everything we've looked at.
+    if let Some(dir) = env.var_os("RUSTC_ENV_STAMP_DIR") {
+        if let Some(krate) = cmd.crate_name() {
+            let extra = cmd.codegen_opt("extra-filename").unwrap_or(String::new());
+            let stamp = Path::new(&dir).join(format!("{}{}.env-stamp", krate, extra));
+            let vars = env.consumed().lines().filter(|l| l.starts_with("RUSTC_"))
+                          .collect::<Vec<_>>().join("\n");
+            let hash = sha256(vars.as_bytes());
+            let changed = stamp.exists() &&
+                          String::from_utf8(read_bytes(&stamp)).unwrap() != hash;
+            match cmd.codegen_opt("incremental") {
+                Some(ref incremental) if changed => {
+                    clear_incremental(Path::new(incremental), krate);
+                }
+                _ => {}
+            }
+            t!(fs::create_dir_all(&dir));
+            t!(t!(File::create(&stamp)).write_all(hash.as_bytes()));
+        }
+    }
+
+    // Dep-info doesn't know anything about the environment variables that
+    // drive this shim, so caches keyed on it can list them from a sidecar.
+    // This needs to come last so it catches everything we've looked at.
+    if env.flag("RUSTC_TRACK_ENV_DEPS") {
+        if let Some(dep_info) = cmd.dep_info() {
+            let mut sidecar = dep_info.into_os_string();
+            sidecar.push(".env");
+            let mut f = t!(File::create(&sidecar));
+            t!(f.write_all(env.consumed().as_bytes()));
+        }
+    }
+
+    return cmd
+}
+
+/// Removes the compiler's incremental state for `krate` from the incremental
+/// directory `dir`, which is shared with other crates.
+fn clear_incremental(dir: &Path, krate: &str) {
+    let prefix = format!("{}-", krate);
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(..) => return,
+    };
+    for entry in entries {
+        let entry = t!(entry);
+        if entry.file_name().to_str().map_or(false, |n| n.starts_with(&prefix)) {
+            t!(fs::remove_dir_all(entry.path()));
+        }
+    }
+}
+
+/// Returns whether `target` produces ELF binaries.
+fn is_elf(target: &str) -> bool {
+    !target.contains("apple") && !target.contains("windows") &&
+        !target.contains("wasm")
+}
+
+/// The time `meta`'s file was last modified.
+fn mtime(meta: &fs::Metadata) -> SystemTime {
+    let mtime = FileTime::from_last_modification_time(meta);
+    UNIX_EPOCH + Duration::new(mtime.seconds_relative_to_1970(), mtime.nanoseconds())
+}
+
+/// Returns whether the newest of the `libstd` modification times is older
+/// than the `compiler`'s, or false if there's no `libstd` at all.
+fn is_stale(compiler: SystemTime, libstd: &[SystemTime]) -> bool {
+    libstd.iter().max().map_or(false, |&newest| newest < compiler)
+}
+
+/// Crates which make up the standard library, as opposed to the compiler.
+const STD_CRATES: &'static [&'static str] = &[
+    "alloc", "alloc_jemalloc", "alloc_system", "collections", "core", "libc",
+    "rand", "rustc_unicode", "std", "std_shim",
+];
+
+fn is_std_crate(krate: &str) -> bool {
+    STD_CRATES.contains(&krate)
+}
+
+/// Returns the value given for the first `flag` in `args`, without requiring
+/// that it's valid UTF-8. Long flags may have their value joined on, as in
+/// `--error-format=json`.
+fn arg_value_os<'a>(args: &'a [OsString], flag: &str) -> Option<&'a OsStr> {
+    for (i, arg) in args.iter().enumerate() {
+        if &**arg == flag {
+            return args.get(i + 1).map(|v| &**v)
+        }
+        if flag.starts_with("--") {
+            if let Some(value) = joined_value(arg, flag) {
+                return Some(value)
+            }
+        }
+    }
+    None
+}
+
+/// Replaces the value given for the first `flag` in `args` with `value`, in
+/// whichever of the forms `arg_value_os` accepts it was passed.
+fn set_arg_value(args: &mut [OsString], flag: &str, value: &OsStr) {
+    for i in 0..args.len() {
+        if &*args[i] == flag && i + 1 < args.len() {
+            args[i + 1] = value.to_owned();
+            return
+        }
+        if flag.starts_with("--") && joined_value(&args[i], flag).is_some() {
+            let mut arg = OsString::from(format!("{}=", flag));
+            arg.push(value);
+            args[i] = arg;
+            return
+        }
+    }
+}
+
+/// Returns the value joined onto `arg` if it's `flag=value`.
+fn joined_value<'a>(arg: &'a OsStr, flag: &str) -> Option<&'a OsStr> {
+    match split_key(arg) {
+        Some((key, value)) if key == flag => Some(value),
+        _ => None,
+    }
+}
+
+/// Splits a `key=value` argument, as long as the key is valid UTF-8.
+#[cfg(unix)]
+fn split_key(arg: &OsStr) -> Option<(&str, &OsStr)> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::str;
+
+    let arg = arg.as_bytes();
+    let i = match arg.iter().position(|&b| b == b'=') {
+        Some(i) => i,
+        None => return None,
+    };
+    str::from_utf8(&arg[..i]).ok().map(|key| (key, OsStr::from_bytes(&arg[i + 1..])))
+}
+
+/// Splits a `key=value` argument. Arguments on Windows can only be taken
+/// apart once we know they're valid Unicode.
+#[cfg(windows)]
+fn split_key(arg: &OsStr) -> Option<(&str, &OsStr)> {
+    arg.to_str().and_then(|arg| {
+        arg.find('=').map(|i| (&arg[..i], OsStr::new(&arg[i + 1..])))
+    })
+}
+
+/// Flags whose values we look at, and so should be valid UTF-8.
+const UTF8_FLAGS: &'static [&'static str] = &[
+    "--crate-name", "--crate-type", "--emit", "--error-format", "--out-dir",
+    "--target", "-C", "-L", "-Z", "-o",
+];
+
+/// Describes each argument in `args` which we need to understand but isn't
+/// valid UTF-8, either as the value of one of the `UTF8_FLAGS` or with its
+/// value joined on, as in `-Cname=value`.
+fn non_utf8_args(args: &[OsString]) -> Vec<String> {
+    let mut ret = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if arg.to_str().is_some() {
+            continue
+        }
+        let lossy = arg.to_string_lossy();
+        let prev = if i > 0 { args[i - 1].to_str() } else { None };
+        let flag = match prev {
+            Some(prev) if UTF8_FLAGS.contains(&prev) => Some(prev),
+            _ => UTF8_FLAGS.iter().find(|f| lossy.starts_with(*f)).cloned(),
+        };
+        if let Some(flag) = flag {
+            ret.push(format!("`{}` argument `{}`", flag, lossy));
+        }
+    }
+    return ret
+}
+
+/// Flags which only take one value, and so shouldn't be passed more than once.
+const SINGLE_VALUED_FLAGS: &'static [&'static str] = &[
+    "--crate-name", "--error-format", "--out-dir", "--sysroot", "--target",
+    "-o",
+];
+
+/// Codegen options which only take one value.
+const SINGLE_VALUED_CODEGEN_OPTS: &'static [&'static str] = &[
+    "codegen-units", "debug-assertions", "debuginfo", "incremental", "linker",
+    "lto", "opt-level", "overflow-checks", "panic", "target-cpu",
+];
+
+/// Returns the first single-valued flag or codegen option in `args` which is
+/// given more than once.
+fn duplicate_flag<'a, I>(args: I) -> Option<String>
+    where I: IntoIterator<Item = &'a OsString>
+{
+    let mut seen = HashSet::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // Only the names matter here, which a lossy conversion leaves alone
+        // even if the values aren't valid UTF-8.
+        let arg = arg.to_string_lossy();
+        let flag = if arg.starts_with("-C") {
+            let opt = if arg == "-C" {
+                args.next().map_or(Cow::Borrowed(""), |a| a.to_string_lossy())
+            } else {
+                Cow::Borrowed(&arg[2..])
+            };
+            let opt = opt.splitn(2, '=').next().unwrap();
+            if !SINGLE_VALUED_CODEGEN_OPTS.contains(&opt) {
+                continue
+            }
+            format!("-C {}", opt)
+        } else {
+            let flag = arg.splitn(2, '=').next().unwrap();
+            if !SINGLE_VALUED_FLAGS.contains(&flag) {
+                continue
+            }
+            flag.to_string()
+        };
+        if !seen.insert(flag.clone()) {
+            return Some(flag)
+        }
+    }
+    return None
+}
+
+/// The number of codegen units the compiler uses if it isn't told otherwise.
+const DEFAULT_CODEGEN_UNITS: usize = 1;
+
+/// Scales down the number of codegen units for a crate whose root source file
+/// is `file_size` bytes: `base` up to `threshold`, and half as many again for
+/// each time the size doubles past that, but always at least one.
+fn scale_cgus(file_size: u64, base: usize, threshold: u64) -> usize {
+    let mut cgus = base;
+    let mut limit = threshold;
+    while file_size > limit && cgus > 1 {
+        cgus /= 2;
+        limit = limit.saturating_mul(2);
+    }
+    return cmp::max(cgus, 1)
+}
+
+/// Infers the Cargo profile being built from its arguments: unoptimized
+/// builds with debuginfo are `dev` and everything else is `release`.
+fn profile(args: &[OsString]) -> &'static str {
+    let opt_level = codegen_opt(args, "opt-level");
+    let debuginfo = args.iter().any(|a| &**a == "-g") ||
+                    codegen_opt(args, "debuginfo").map_or(false, |d| d != "0");
+    if opt_level.map_or(true, |o| o == "0") && debuginfo {
+        "dev"
+    } else {
+        "release"
+    }
+}
+
+/// Returns the path of the stamp file `<prefix>-<target>.stamp` in `sysroot`.
+///
+/// A custom target given as the path of its spec is named by the spec's file
+/// stem and a hash of the whole path instead, so the stamp stays directly in
+/// `sysroot` and specs with the same name in different places don't collide.
+fn target_stamp(sysroot: &Path, prefix: &str, target: &OsStr) -> PathBuf {
+    let path = Path::new(target);
+    let mut name = OsString::from(format!("{}-", prefix));
+    if path.extension() == Some(OsStr::new("json")) {
+        let hash = sha256(target.to_string_lossy().as_bytes());
+        name.push(path.file_stem().unwrap());
+        name.push(format!("-{}", &hash[..16]));
+    } else {
+        name.push(target);
+    }
+    name.push(".stamp");
+    return sysroot.join(name)
+}
+
+/// Returns the target features `rustc` knows about for `target`, caching the
+/// list in `sysroot` so the compiler only needs asking once.
+fn target_features(rustc: &OsStr, target: &OsStr, sysroot: &Path) -> Vec<String> {
+    let stamp = target_stamp(sysroot, "target-features", target);
+    let output = if stamp.exists() {
+        String::from_utf8(read_bytes(&stamp)).unwrap()
+    } else {
+        let mut cmd = Command::new(rustc);
+        cmd.arg("--print").arg("target-features").arg("--target").arg(target);
+        let output = match cmd.output() {
+            Ok(output) => output,
+            Err(e) => panic!("\n\nfailed to run {:?}: {}\n\n", cmd, e),
+        };
+        if !output.status.success() {
+            panic!("\n\n{:?} failed:\n{}\n\n", cmd,
+                   String::from_utf8_lossy(&output.stderr));
+        }
+        let output = String::from_utf8(output.stdout).unwrap();
+        t!(fs::create_dir_all(sysroot));
+        write_atomically(&stamp, output.as_bytes());
+        output
+    };
+
+    // Features are listed indented, one per line, followed by a description.
+    output.lines().filter(|l| l.starts_with(" ") || l.starts_with("\t"))
+          .filter_map(|l| l.split_whitespace().next())
+          .map(|s| s.to_string())
+          .collect()
+}
+
+/// Writes a copy of the target spec at `spec` with each `field=value` in
+/// `overrides` applied, returning its path.
+///
+/// Values are parsed as JSON if they can be and taken as strings otherwise,
+/// and must be of the same kind as any value they replace. The copy has the
+/// same file name as the original, as that's where the target's name comes
+/// from, in a directory named after its contents.
+fn override_target_spec(spec: &Path, overrides: &[String]) -> PathBuf {
+    let contents = String::from_utf8(read_bytes(spec)).unwrap();
+    let mut fields = match Json::from_str(&contents) {
+        Ok(Json::Object(fields)) => fields,
+        Ok(..) => panic!("target spec {} isn't a JSON object", spec.display()),
+        Err(e) => panic!("failed to parse target spec {}: {}", spec.display(), e),
+    };
+    for entry in overrides {
+        let mut parts = entry.splitn(2, '=');
+        let (field, value) = match (parts.next(), parts.next()) {
+            (Some(field), Some(value)) if !field.is_empty() => (field, value),
+            _ => panic!("invalid RUSTC_TARGET_SPEC_OVERRIDES entry `{}`, \
+                         expected `field=value`", entry),
+        };
+        let value = Json::from_str(value).unwrap_or(Json::String(value.to_string()));
+        if let Some(old) = fields.get(field) {
+            if json_kind(old) != json_kind(&value) {
+                panic!("invalid value `{}` for target spec field `{}`, \
+                        expected {}", value, field, json_kind(old));
+            }
+        }
+        fields.insert(field.to_string(), value);
+    }
+
+    let patched = Json::Object(fields).to_string();
+    let dir = env::temp_dir().join(format!("rustc-target-spec-{}",
+                                           &sha256(patched.as_bytes())[..16]));
+    t!(fs::create_dir_all(&dir));
+    // The path's content-addressed, so if it's already there it's already
+    // right, and otherwise it's written so nobody sees it half done.
+    let path = dir.join(spec.file_name().unwrap());
+    if !path.exists() {
+        write_atomically(&path, patched.as_bytes());
+    }
+    return path
+}
+
+fn json_kind(json: &Json) -> &'static str {
+    match *json {
+        Json::I64(..) | Json::U64(..) | Json::F64(..) => "a number",
+        Json::String(..) => "a string",
+        Json::Boolean(..) => "a boolean",
+        Json::Array(..) => "an array",
+        Json::Object(..) => "an object",
+        Json::Null => "null",
+    }
+}
+
+/// Returns the output of `pkg-config --libs` for `pkgs`, using the copy
+/// cached in `stamp_dir` if there is one.
+fn pkg_config_libs(env: &Env, pkgs: &[String], stamp_dir: Option<&PathBuf>)
+                   -> String {
+    let stamp = stamp_dir.map(|dir| {
+        dir.join(format!(".pkg-config-{}.stamp", pkgs.join("_")))
+    });
+    if let Some(ref stamp) = stamp {
+        if stamp.exists() {
+            return String::from_utf8(read_bytes(stamp)).unwrap()
+        }
+    }
+
+    let pkg_config = env.var_os("PKG_CONFIG")
+                        .unwrap_or(OsString::from("pkg-config"));
+    let mut cmd = Command::new(pkg_config);
+    cmd.arg("--libs").args(pkgs);
+    let output = match cmd.output() {
+        Ok(output) => output,
+        Err(e) => panic!("\n\nfailed to run {:?}: {}\n\n", cmd, e),
+    };
+    if !output.status.success() {
+        panic!("\n\n{:?} failed:\n{}\n\n", cmd,
+               String::from_utf8_lossy(&output.stderr));
+    }
+    let libs = String::from_utf8(output.stdout).unwrap();
+    if let Some(ref stamp) = stamp {
+        write_atomically(stamp, libs.as_bytes());
+    }
+    return libs
+}
+
+/// Returns `args` without any `-C incremental` options, whether passed as
+/// `-C incremental=path` or `-Cincremental=path`.
+fn strip_incremental(args: &[OsString]) -> Vec<OsString> {
+    fn is_incremental(opt: &str) -> bool {
+        opt == "incremental" || opt.starts_with("incremental=")
+    }
+
+    // The incremental directory needn't be valid UTF-8, so this goes by a
+    // lossy conversion, which leaves the option's name intact.
+    let mut ret = Vec::new();
+    let mut i = 0;
+    while i < args.len() {
+        let next = args.get(i + 1).map(|a| a.to_string_lossy());
+        let arg = args[i].to_string_lossy();
+        if arg == "-C" && next.as_ref().map_or(false, |n| is_incremental(n)) {
+            i += 1;
+        } else if !(arg.starts_with("-C") && is_incremental(&arg[2..])) {
+            ret.push(args[i].clone());
+        }
+        i += 1;
+    }
+    return ret
+}
+
+/// Moves the compiler's outputs into a `stage` subdirectory of Cargo's output
+/// directory, creating the directories as we go.
+///
+/// Along with the `--out-dir` and `-o` paths themselves, this rewrites the
+/// `-L` and `--extern` paths which point into the output directory, so each
+/// crate finds the dependencies built for the same stage rather than those
+/// Cargo expects to be next to it.
+fn per_stage_out_dir(args: &mut [OsString], stage: &str) {
+    let out_dir = arg_value_os(args, "--out-dir").map(PathBuf::from);
+    let staged = |path: &Path| -> Option<PathBuf> {
+        out_dir.as_ref().and_then(|dir| path.strip_prefix(dir).ok().map(|rest| {
+            if rest.as_os_str().is_empty() {
+                dir.join(stage)
+            } else {
+                dir.join(stage).join(rest)
+            }
+        }))
+    };
+    let keyed = |key: &str, path: &Path| {
+        let mut arg = OsString::from(format!("{}=", key));
+        arg.push(path);
+        arg
+    };
+
+    for i in 1..args.len() {
+        let new = match args[i - 1].to_str() {
+            Some("--out-dir") => {
+                let dir = Path::new(&args[i]).join(stage);
+                t!(fs::create_dir_all(&dir));
+                dir.into_os_string()
+            }
+            Some("-o") => {
+                let path = PathBuf::from(&args[i]);
+                let file = path.file_name().expect("-o without a file name");
+                let new = staged(&path).unwrap_or_else(|| {
+                    path.parent().unwrap_or(Path::new("")).join(stage).join(file)
+                });
+                t!(fs::create_dir_all(new.parent().unwrap()));
+                new.into_os_string()
+            }
+            Some("-L") | Some("--extern") => {
+                let new = match split_key(&args[i]) {
+                    Some((key, path)) => {
+                        staged(Path::new(path)).map(|p| keyed(key, &p))
+                    }
+                    None => staged(Path::new(&args[i])).map(|p| p.into_os_string()),
+                };
+                match new {
+                    Some(new) => new,
+                    None => continue,
+                }
+            }
+            _ => continue,
+        };
+        args[i] = new;
+    }
+}
+
+/// Rewrites the paths of the `--extern name=path` arguments in `args` to the
+/// ones given by `resolver`.
+///
+/// The resolver is run once with each extern as a `name=path` argument, and
+/// must print a `name=path` line for each of them, in the same order.
+fn resolve_externs(resolver: &OsStr, args: &mut [OsString]) {
+    // Paths needn't be valid UTF-8, so they're passed to the resolver as they
+    // are, and only the names are looked at.
+    let externs = (1..args.len()).filter(|&i| {
+        &*args[i - 1] == "--extern" && args[i].to_string_lossy().contains('=')
+    }).collect::<Vec<_>>();
+    if externs.is_empty() {
+        return
+    }
+
+    let mut cmd = Command::new(resolver);
+    for &i in externs.iter() {
+        cmd.arg(&args[i]);
+    }
+    let output = match cmd.output() {
+        Ok(output) => output,
+        Err(e) => panic!("\n\nfailed to run {:?}: {}\n\n", cmd, e),
+    };
+    if !output.status.success() {
+        panic!("\n\n{:?} failed:\n{}\n\n", cmd,
+               String::from_utf8_lossy(&output.stderr));
+    }
+    let output = match String::from_utf8(output.stdout) {
+        Ok(s) => s,
+        Err(..) => panic!("RUSTC_EXTERN_RESOLVER printed invalid UTF-8"),
+    };
+    let lines = output.lines().collect::<Vec<_>>();
+    if lines.len() != externs.len() {
+        panic!("RUSTC_EXTERN_RESOLVER printed {} lines for {} externs",
+               lines.len(), externs.len());
+    }
+
+    for (&i, line) in externs.iter().zip(lines) {
+        let name = args[i].to_string_lossy().splitn(2, '=').next().unwrap()
+                          .to_string();
+        let mut parts = line.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(n), Some(path)) if n == name && !path.is_empty() => {}
+            _ => panic!("invalid RUSTC_EXTERN_RESOLVER output `{}`, expected \
+                         `{}=path`", line, name),
+        }
+        args[i] = OsString::from(line);
+    }
+}
+
+/// Resolves symlinks in the `--out-dir`, `--sysroot` and `-L` paths in
+/// `args`, leaving alone any that don't exist.
+fn canonicalize_paths(args: &mut [OsString]) {
+    fn canonicalize(path: &str) -> String {
+        match fs::canonicalize(path) {
+            Ok(path) => path.to_str().map(|s| s.to_string())
+                            .unwrap_or(path.to_string_lossy().into_owned()),
+            Err(..) => path.to_string(),
+        }
+    }
+
+    for i in 1..args.len() {
+        let path = match (args[i - 1].to_str(), args[i].to_str()) {
+            (Some("--out-dir"), Some(path)) |
+            (Some("--sysroot"), Some(path)) => canonicalize(path),
+            (Some("-L"), Some(path)) => {
+                let mut parts = path.splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some(kind), Some(path)) => {
+                        format!("{}={}", kind, canonicalize(path))
+                    }
+                    _ => canonicalize(path),
+                }
+            }
+            _ => continue,
+        };
+        args[i] = OsString::from(path);
+    }
+}
+
+/// Runs the compiler, returning the code the shim should exit with.
+fn run(cmd: &Invocation) -> i32 {
+    let krate = cmd.crate_name().unwrap_or("unknown").to_string();
+    let mut command = cmd.command();
+    let log = cmd.combined_log.as_ref().map(|path| {
+        Arc::new(Mutex::new(t!(File::create(path))))
+    });
+    // A compiler in a process group of its own mustn't touch the terminal,
+    // so its output always comes through us then.
+    let grouped = cfg!(unix) && cmd.timeout.is_some();
+    let capture_stdout = cmd.fuel_log.is_some() || cmd.drop_glue_log.is_some() ||
+                         log.is_some() || grouped;
+    if capture_stdout {
+        command.stdout(Stdio::piped());
+    }
+    let diagnostics = cmd.sarif.is_some() || cmd.dedup_diagnostics ||
+                      cmd.json_out.is_some();
+    let capture_stderr = diagnostics || log.is_some() || grouped;
+    if capture_stderr {
+        command.stderr(Stdio::piped());
+    }
+    let start = Instant::now();
+    let mut tree = cmd.timeout.map(|_| ProcessTree::new());
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) => panic!("\n\nfailed to run {:?}: {}\n\n", command, e),
+    };
+    let watchdog = match (tree.take(), cmd.timeout) {
+        (Some(mut tree), Some(timeout)) => {
+            tree.adopt(&child);
+            Some(Watchdog::start(tree, timeout))
+        }
+        _ => None,
+    };
+
+    // Each captured stream gets a thread of its own so the compiler can't
+    // block on a full pipe while we're busy with the other one, or waiting
+    // for it to finish.
+    let stdout = if capture_stdout {
+        let stdout = Tee { inner: child.stdout.take().unwrap(), log: log.clone() };
+        let stdout = BufReader::new(stdout);
+        let krate = krate.clone();
+        let fuel_log = cmd.fuel_log.clone();
+        let drop_glue_log = cmd.drop_glue_log.clone();
+        Some(thread::spawn(move || {
+            forward_stdout(stdout, &krate, fuel_log.as_ref().map(|p| &**p),
+                           drop_glue_log.as_ref().map(|p| &**p))
+        }))
+    } else {
+        None
+    };
+    let stderr = if capture_stderr {
+        let mut stderr = Tee { inner: child.stderr.take().unwrap(), log: log };
+        let sarif = cmd.sarif.clone();
+        let json = cmd.forward_json;
+        let dedup = cmd.dedup_diagnostics;
+        let json_out = cmd.json_out.clone();
+        Some(thread::spawn(move || {
+            let err = io::stderr();
+            if diagnostics {
+                forward_diagnostics(BufReader::new(stderr), err.lock(),
+                                    sarif.as_ref().map(|p| &**p), json,
+                                    json_out.as_ref().map(|p| &**p), dedup)
+            } else {
+                t!(io::copy(&mut stderr, &mut err.lock()));
+            }
+        }))
+    } else {
+        None
+    };
+
+    let status = t!(child.wait());
+    release_signals();
+    if watchdog.map_or(false, |w| w.finish()) {
+        let _ = writeln!(io::stderr(), "error: compiling `{}` timed out \
+                                        after {} seconds", krate,
+                         cmd.timeout.unwrap().as_secs());
+        record_exit(cmd, &krate, "timeout", None);
+        return TIMEOUT_EXIT_CODE
+    }
+    join(stdout, "stdout");
+    join(stderr, "stderr");
+
+    if let Some(threshold) = cmd.slow_compile {
+        if let Some(warning) = slow_compile(&krate, start.elapsed(), threshold) {
+            let _ = writeln!(io::stderr(), "warning: {}", warning);
+        }
+    }
+
+    record_exit(cmd, &krate, &describe_exit(&status), status.code());
+    let code = status.code().unwrap_or(1);
+    if code == ICE_EXIT_CODE {
+        if let Some(ref dir) = cmd.ice_repro_dir {
+            save_ice_repro(cmd, dir, &krate);
+        }
+    }
+    if code != 0 {
+        return code
+    }
+
+    if let Some(ref merged) = cmd.merged_dep_info {
+        if let Some(dep_info) = cmd.dep_info() {
+            if dep_info.exists() {
+                let _lock = Lock::new(merged);
+                merge_dep_info(merged, &dep_info);
+            }
+        }
+    }
+
+    if cmd.reproducible_check {
+        let artifact = cmd.artifact().expect("couldn't find artifact to check");
+        if !reproducible(cmd, &artifact) {
+            return 1
+        }
+    }
+
+    if let Some((ref objcopy, ref args)) = cmd.objcopy {
+        let artifact = cmd.artifact().expect("couldn't find artifact for objcopy");
+        let mut objcopy = Command::new(objcopy);
+        objcopy.args(args).arg(&artifact);
+        let code = match objcopy.status() {
+            Ok(s) => s.code().unwrap_or(1),
+            Err(e) => panic!("\n\nfailed to run {:?}: {}\n\n", objcopy, e),
+        };
+        if code != 0 {
+            let _ = writeln!(io::stderr(), "error: objcopy failed on {}",
+                             artifact.display());
+            return code
+        }
+    }
+
+    // This goes last so the hash is of the artifact as it's finally left.
+    if let Some(ref manifest) = cmd.hash_manifest {
+        let artifact = cmd.artifact().expect("couldn't find artifact to hash");
+        let hash = sha256(&read_bytes(&artifact));
+        let _lock = Lock::new(manifest);
+        append_line(manifest, &format!("{}  {}", hash, artifact.display()));
+    }
+
+    return code
+}
+
+/// Adds the rules in the makefile-style dep-info file `dep_info` to those in
+/// `merged`, leaving out any we've already got.
+fn merge_dep_info(merged: &Path, dep_info: &Path) {
+    let mut rules = if merged.exists() {
+        parse_dep_info(&String::from_utf8(read_bytes(merged)).unwrap())
+    } else {
+        Vec::new()
+    };
+    let new = parse_dep_info(&String::from_utf8(read_bytes(dep_info)).unwrap());
+    for (target, deps) in new {
+        let i = match rules.iter().position(|&(ref t, _)| *t == target) {
+            Some(i) => i,
+            None => {
+                rules.push((target, Vec::new()));
+                rules.len() - 1
+            }
+        };
+        for dep in deps {
+            if !rules[i].1.contains(&dep) {
+                rules[i].1.push(dep);
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for &(ref target, ref deps) in rules.iter() {
+        out.push_str(target);
+        out.push(':');
+        for dep in deps {
+            out.push(' ');
+            out.push_str(dep);
+        }
+        out.push_str("\n\n");
+    }
+    t!(t!(File::create(merged)).write_all(out.as_bytes()));
+}
+
+/// Parses the rules out of a makefile-style dep-info file. Paths are left
+/// escaped as they were, so they can be written straight back out.
+fn parse_dep_info(contents: &str) -> Vec<(String, Vec<String>)> {
+    let contents = contents.replace("\\\r\n", " ").replace("\\\n", " ");
+    let mut rules = Vec::new();
+    for line in contents.lines() {
+        let mut words = split_unescaped(line).into_iter();
+        let target = match words.next() {
+            Some(ref word) if word.ends_with(":") => {
+                word[..word.len() - 1].to_string()
+            }
+            Some(word) => {
+                match words.next() {
+                    Some(ref colon) if colon == ":" => word,
+                    _ => continue,
+                }
+            }
+            None => continue,
+        };
+        rules.push((target, words.collect()));
+    }
+    return rules
+}
+
+/// Splits `line` on the spaces which aren't escaped with a backslash.
+fn split_unescaped(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut escaped = false;
+    for c in line.chars() {
+        if c == ' ' && !escaped {
+            if !word.is_empty() {
+                words.push(word);
+                word = String::new();
+            }
+        } else {
+            word.push(c);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    return words
+}
+
+/// A lock on a file shared between parallel invocations of the shim, held
+/// on a `.lock` file next to it for as long as this is alive.
+///
+/// The lock is the operating system's, so it's released as soon as we exit
+/// for whatever reason and a crashed invocation can't leave it stuck. The
+/// `.lock` file itself is left behind, as removing it would let the next
+/// invocation lock a new file while another is still waiting on the old one.
+struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    fn new(path: &Path) -> Lock {
+        let mut lock = path.as_os_str().to_owned();
+        lock.push(".lock");
+        let lock = PathBuf::from(lock);
+        let file = t!(OpenOptions::new().write(true).create(true).open(&lock));
+        if let Err(e) = lock_file(&file) {
+            panic!("failed to lock {}: {}", lock.display(), e);
+        }
+        Lock { _file: file }
+    }
+}
+
+/// Blocks until we've got an exclusive lock on `file`, which lasts until it's
+/// closed.
+#[cfg(unix)]
+fn lock_file(file: &File) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    loop {
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == 0 {
+            return Ok(())
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return Err(err)
+        }
+    }
+}
+
+/// Blocks until we've got an exclusive lock on `file`, which lasts until it's
+/// closed.
+#[cfg(windows)]
+fn lock_file(file: &File) -> io::Result<()> {
+    use std::mem;
+    use std::os::windows::io::AsRawHandle;
+
+    unsafe {
+        let mut overlapped = mem::zeroed::<winapi::OVERLAPPED>();
+        let r = kernel32::LockFileEx(file.as_raw_handle() as winapi::HANDLE,
+                                     winapi::LOCKFILE_EXCLUSIVE_LOCK, 0,
+                                     !0, !0, &mut overlapped);
+        if r == 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// The code we exit with if the compiler doesn't finish in time, which is the
+/// same one coreutils' `timeout` uses.
+const TIMEOUT_EXIT_CODE: i32 = 124;
+
+/// The code the compiler exits with when it panics, i.e. on an ICE.
+const ICE_EXIT_CODE: i32 = 101;
+
+/// Saves the compiler, its arguments and the environment variables that may
+/// have affected it to `<dir>/<krate>-<timestamp>.repro`, so that an ICE can
+/// be replayed later.
+fn save_ice_repro(cmd: &Invocation, dir: &Path, krate: &str) {
+    let mut repro = String::from("# compiler\n");
+    repro.push_str(&cmd.rustc.to_string_lossy());
+    repro.push_str("\n# args\n");
+    for arg in cmd.args.iter().chain(&cmd.added) {
+        repro.push_str(&arg.to_string_lossy());
+        repro.push('\n');
+    }
+    repro.push_str("# env\n");
+    let vars = env::vars_os().filter(|&(ref k, _)| {
+        k.to_str().map_or(false, |k| k.starts_with("RUST") || k.starts_with("CARGO"))
+    }).chain(cmd.env.iter().cloned()).collect::<BTreeMap<_, _>>();
+    for (k, v) in vars {
+        repro.push_str(&format!("{}={}\n", k.to_string_lossy(), v.to_string_lossy()));
+    }
+
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+                                     .map(|d| d.as_secs()).unwrap_or(0);
+    let path = dir.join(format!("{}-{}.repro", krate, timestamp));
+    t!(fs::create_dir_all(dir));
+    t!(t!(File::create(&path)).write_all(repro.as_bytes()));
+    let _ = writeln!(io::stderr(), "note: saved what's needed to reproduce \
+                                    this ICE to {}", path.display());
+}
+
+/// Kills the compiler, along with anything it has spawned, if it's still
+/// running once `timeout` is up.
+struct Watchdog {
+    done: Arc<(Mutex<bool>, Condvar)>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(tree: ProcessTree, timeout: Duration) -> Watchdog {
+        let done = Arc::new((Mutex::new(false), Condvar::new()));
+        let done2 = done.clone();
+        let thread = thread::spawn(move || {
+            let &(ref lock, ref cvar) = &*done2;
+            let start = Instant::now();
+            let mut done = lock.lock().unwrap();
+            while !*done {
+                let elapsed = start.elapsed();
+                if elapsed >= timeout {
+                    tree.kill();
+                    return true
+                }
+                done = cvar.wait_timeout(done, timeout - elapsed).unwrap().0;
+            }
+            false
+        });
+        Watchdog { done: done, thread: thread }
+    }
+
+    /// Stops the watchdog once the compiler has exited, returning whether it
+    /// had to kill it.
+    fn finish(self) -> bool {
+        {
+            let &(ref lock, ref cvar) = &*self.done;
+            *lock.lock().unwrap() = true;
+            cvar.notify_one();
+        }
+        self.thread.join().unwrap()
+    }
+}
+
+/// The compiler and everything it spawns (linkers, `cc`, ...), so that a
+/// timeout doesn't leave any of them behind.
+///
+/// On Unix the child's process group would normally be set between `fork` and
+/// `exec`, but `CommandExt::before_exec` is newer than our stage0 compiler.
+/// Instead we briefly move ourselves into a new process group around the
+/// `spawn` for the compiler to inherit, and then move straight back. The
+/// group is named after our own pid, so it can't be reused while we're
+/// around to signal it. If we're a session or process group leader already
+/// we can't do this, and only the compiler itself gets killed.
+///
+/// The group isn't the terminal's foreground group, so the terminal's
+/// signals only reach us: we pass on `SIGINT`, `SIGTERM` and `SIGHUP`, and
+/// stop and continue the group along with ourselves. Its output is piped
+/// through us as well (see `run`), so it can't be stopped for writing to
+/// the terminal from the background either.
+#[cfg(unix)]
+struct ProcessTree {
+    parent_group: Option<libc::pid_t>,
+    child: libc::pid_t,
+}
+
+/// The compiler's process group while there's one to pass signals on to.
+#[cfg(unix)]
+static GROUP: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+#[cfg(unix)]
+const FORWARDED_SIGNALS: &'static [libc::c_int] = &[
+    libc::SIGINT, libc::SIGTERM, libc::SIGHUP, libc::SIGTSTP, libc::SIGCONT,
+];
+
+#[cfg(unix)]
+extern "C" fn forward_signal(signal: libc::c_int) {
+    let group = GROUP.load(Ordering::SeqCst) as libc::pid_t;
+    if group == 0 {
+        return
+    }
+    unsafe {
+        if signal == libc::SIGTSTP {
+            // The default action is lost along with the handler, so stop
+            // ourselves by hand once the compiler's been stopped.
+            libc::kill(-group, libc::SIGSTOP);
+            libc::kill(libc::getpid(), libc::SIGSTOP);
+        } else {
+            libc::kill(-group, signal);
+        }
+    }
+}
+
+/// Stops passing signals on to the compiler's process group once it's gone.
+#[cfg(unix)]
+fn release_signals() {
+    if GROUP.swap(0, Ordering::SeqCst) != 0 {
+        for &signal in FORWARDED_SIGNALS {
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+fn release_signals() {}
+
+#[cfg(unix)]
+fn stderr_is_tty() -> bool {
+    unsafe { libc::isatty(2) == 1 }
+}
+
+#[cfg(windows)]
+fn stderr_is_tty() -> bool {
+    false
+}
+
+#[cfg(unix)]
+impl ProcessTree {
+    /// Must be called right before spawning the compiler.
+    fn new() -> ProcessTree {
+        unsafe {
+            let pid = libc::getpid();
+            let parent = libc::getpgrp();
+            let grouped = parent != pid && libc::setpgid(0, 0) == 0;
+            ProcessTree {
+                parent_group: if grouped { Some(parent) } else { None },
+                child: 0,
+            }
+        }
+    }
+
+    /// Must be called right after spawning the compiler.
+    fn adopt(&mut self, child: &Child) {
+        self.child = child.id() as libc::pid_t;
+        if let Some(parent) = self.parent_group {
+            unsafe {
+                if libc::setpgid(0, parent) != 0 {
+                    panic!("failed to leave the compiler's process group: {}",
+                           io::Error::last_os_error());
+                }
+                GROUP.store(libc::getpid() as isize, Ordering::SeqCst);
+                let handler = forward_signal as extern "C" fn(libc::c_int);
+                for &signal in FORWARDED_SIGNALS {
+                    libc::signal(signal, handler as libc::sighandler_t);
+                }
+            }
+        }
+    }
+
+    fn kill(&self) {
+        unsafe {
+            match self.parent_group {
+                Some(_) => libc::kill(-libc::getpid(), libc::SIGKILL),
+                None => libc::kill(self.child, libc::SIGKILL),
+            };
+        }
+    }
+}
+
+/// The compiler and everything it spawns (linkers, `cc`, ...), so that a
+/// timeout doesn't leave any of them behind.
+///
+/// This is a job object the compiler gets assigned to once it's started, so
+/// anything it spawns from then on ends up in the job as well. Assigning it
+/// can fail if we're in a job ourselves on versions of Windows without nested
+/// jobs, in which case only the compiler itself gets killed.
+#[cfg(windows)]
+struct ProcessTree {
+    job: winapi::HANDLE,
+    child: winapi::HANDLE,
+}
+
+// Both handles are only ever used from the watchdog thread once it's started.
+#[cfg(windows)]
+unsafe impl Send for ProcessTree {}
+
+#[cfg(windows)]
+impl ProcessTree {
+    fn new() -> ProcessTree {
+        let job = unsafe { kernel32::CreateJobObjectW(0 as *mut _, 0 as *const _) };
+        assert!(job != 0 as *mut _, "{}", io::Error::last_os_error());
+        ProcessTree { job: job, child: 0 as *mut _ }
+    }
+
+    fn adopt(&mut self, child: &Child) {
+        use std::os::windows::io::AsRawHandle;
+
+        self.child = child.as_raw_handle() as winapi::HANDLE;
+        if unsafe { kernel32::AssignProcessToJobObject(self.job, self.child) } == 0 {
+            unsafe { kernel32::CloseHandle(self.job); }
+            self.job = 0 as *mut _;
+        }
+    }
+
+    fn kill(&self) {
+        unsafe {
+            if self.job.is_null() {
+                kernel32::TerminateProcess(self.child, 1);
+            } else {
+                kernel32::TerminateJobObject(self.job, 1);
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ProcessTree {
+    fn drop(&mut self) {
+        if !self.job.is_null() {
+            unsafe { kernel32::CloseHandle(self.job); }
+        }
+    }
+}
+
+fn join(thread: Option<thread::JoinHandle<()>>, stream: &str) {
+    if let Some(thread) = thread {
+        if thread.join().is_err() {
+            panic!("failed to forward the compiler's {}", stream);
+        }
+    }
+}
+
+/// Records how the compiler exited in the exit status file and the JSON
+/// stats, if we've been asked to keep either.
+fn record_exit(cmd: &Invocation, krate: &str, exit: &str, code: Option<i32>) {
+    if let Some(ref file) = cmd.exit_status_file {
+        append_line(file, &format!("crate={} target={} stage={} exit={}", krate,
+                                   cmd.target.as_ref().map_or("host", |s| s),
+                                   cmd.stage, exit));
+    }
+    if let Some(ref file) = cmd.stats_json {
+        let added = cmd.added.iter().map(|a| a.to_string_lossy().into_owned())
+                             .collect::<Vec<_>>();
+        let stats = object(&[
+            ("crate", krate.to_json()),
+            ("stage", cmd.stage.to_json()),
+            ("target", cmd.target.to_json()),
+            ("compiler", cmd.rustc.to_string_lossy().to_json()),
+            ("added_flags", added.to_json()),
+            ("exit_code", code.to_json()),
+        ]);
+        append_line(file, &stats.to_string());
+    }
+}
+
+/// Returns a warning about compiling `krate` having taken `elapsed`, if
+/// that's longer than `threshold`.
+fn slow_compile(krate: &str, elapsed: Duration, threshold: Duration)
+                -> Option<String> {
+    if elapsed <= threshold {
+        return None
+    }
+    let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9;
+    return Some(format!("compiling `{}` took {:.1}s, more than the {}s \
+                         allowed by RUSTC_SLOW_COMPILE_SECS", krate, secs,
+                        threshold.as_secs()))
+}
+
+/// Compiles the crate a second time into a scratch directory and checks that
+/// the artifact comes out byte-for-byte identical to the one at `artifact`.
+fn reproducible(cmd: &Invocation, artifact: &Path) -> bool {
+    let name = artifact.file_name().unwrap();
+    let mut scratch = artifact.as_os_str().to_owned();
+    scratch.push(".reproducible-check");
+    let scratch = PathBuf::from(scratch);
+    let _ = fs::remove_dir_all(&scratch);
+    t!(fs::create_dir_all(&scratch));
+
+    // The rebuild mustn't share Cargo's incremental cache, which would hand
+    // it the first build's results, nor repeat its diagnostics.
+    let mut args = strip_incremental(&cmd.args);
+    for i in 1..args.len() {
+        if &*args[i - 1] == "--out-dir" {
+            args[i] = scratch.clone().into_os_string();
+        } else if &*args[i - 1] == "-o" {
+            args[i] = scratch.join(name).into_os_string();
+        }
+    }
+    let mut rebuild = cmd.command_with(&args);
+    rebuild.stdout(Stdio::null()).stderr(Stdio::null());
+    let status = match rebuild.status() {
+        Ok(s) => s,
+        Err(e) => panic!("\n\nfailed to run {:?}: {}\n\n", rebuild, e),
+    };
+
+    let rebuilt = scratch.join(name);
+    let same = status.success() && read_bytes(artifact) == read_bytes(&rebuilt);
+    if !same {
+        let _ = writeln!(io::stderr(), "error: {} is not reproducible, compare \
+                                        it with {}", artifact.display(),
+                         rebuilt.display());
+    } else {
+        let _ = fs::remove_dir_all(&scratch);
+    }
+    return same
+}
+
+/// Returns the SHA-256 hash of `data` as lowercase hex, as `sha256sum` does.
+///
+/// This is the straightforward implementation from FIPS 180-4, which is
+/// plenty fast enough for hashing artifacts one at a time.
+fn sha256(data: &[u8]) -> String {
+    const K: [u32; 64] = [
+        0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1,
+        0x923f82a4, 0xab1c5ed5, 0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3,
+        0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174, 0xe49b69c1, 0xefbe4786,
+        0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+        0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147,
+        0x06ca6351, 0x14292967, 0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13,
+        0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85, 0xa2bfe8a1, 0xa81a664b,
+        0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+        0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a,
+        0x5b9cca4f, 0x682e6ff3, 0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208,
+        0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+    ];
+    let mut h: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c,
+        0x1f83d9ab, 0x5be0cd19,
+    ];
+
+    // Pad to a multiple of 64 bytes with a 1 bit, zeros, and then the
+    // message length in bits.
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    let bits = (data.len() as u64).wrapping_mul(8);
+    for i in 0..8 {
+        msg.push((bits >> (56 - i * 8)) as u8);
+    }
+
+    for block in msg.chunks(64) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = (block[i * 4] as u32) << 24 | (block[i * 4 + 1] as u32) << 16 |
+                   (block[i * 4 + 2] as u32) << 8 | block[i * 4 + 3] as u32;
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^
+                     (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^
+                     (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7])
+                            .wrapping_add(s1);
+        }
+
+        let mut v = h;
+        for i in 0..64 {
+            let s1 = v[4].rotate_right(6) ^ v[4].rotate_right(11) ^
+                     v[4].rotate_right(25);
+            let ch = (v[4] & v[5]) ^ (!v[4] & v[6]);
+            let t1 = v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i])
+                         .wrapping_add(w[i]);
+            let s0 = v[0].rotate_right(2) ^ v[0].rotate_right(13) ^
+                     v[0].rotate_right(22);
+            let maj = (v[0] & v[1]) ^ (v[0] & v[2]) ^ (v[1] & v[2]);
+            let t2 = s0.wrapping_add(maj);
+            v = [t1.wrapping_add(t2), v[0], v[1], v[2], v[3].wrapping_add(t1),
+                 v[4], v[5], v[6]];
+        }
+        for i in 0..8 {
+            h[i] = h[i].wrapping_add(v[i]);
+        }
+    }
+
+    return h.iter().map(|x| format!("{:08x}", x)).collect()
+}
+
+fn read_bytes(path: &Path) -> Vec<u8> {
+    let mut contents = Vec::new();
+    t!(t!(File::open(path)).read_to_end(&mut contents));
+    return contents
+}
+
+/// Writes `contents` to `path` by way of a temporary file next to it, so that
+/// parallel invocations reading it never see it half written.
+fn write_atomically(path: &Path, contents: &[u8]) {
+    let mut i = 0;
+    let (tmp, mut file) = loop {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(format!(".tmp{}", i));
+        let tmp = PathBuf::from(tmp);
+        match OpenOptions::new().write(true).create_new(true).open(&tmp) {
+            Ok(file) => break (tmp, file),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => i += 1,
+            Err(e) => panic!("failed to create {}: {}", tmp.display(), e),
+        }
+    };
+    t!(file.write_all(contents));
+    drop(file);
+    t!(fs::rename(&tmp, path));
+}
+
+/// Describes how a process exited: with its exit code if it has one, or with
+/// the signal that killed it otherwise.
+fn describe_exit(status: &ExitStatus) -> String {
+    if let Some(code) = status.code() {
+        return code.to_string()
+    }
+    signal(status)
+}
+
+#[cfg(unix)]
+fn signal(status: &ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+    match status.signal() {
+        Some(signal) => format!("signal:{}", signal),
+        None => "unknown".to_string(),
+    }
+}
+
+#[cfg(not(unix))]
+fn signal(_status: &ExitStatus) -> String {
+    "unknown".to_string()
+}
+
+/// Forwards the compiler's stdout to our own, picking out the fuel consumption
+/// reported by `-Z print-fuel` and the drop glue listed by
+/// `-Z print-trans-items` along the way and recording them in their logs.
+fn forward_stdout<R: BufRead>(stdout: R,
+                              krate: &str,
+                              fuel_log: Option<&Path>,
+                              drop_glue_log: Option<&Path>) {
+    let out = io::stdout();
+    let mut out = out.lock();
+    let (mut drop_glue, mut drop_glue_contents) = (0, 0);
+    for line in stdout.lines() {
+        let line = t!(line);
+        if let Some(log) = fuel_log {
+            if line.starts_with("Fuel used by ") {
+                if let Some(fuel) = line.rsplit(": ").next() {
+                    append_line(log, &format!("{}: {}", krate, fuel));
+                }
+            }
+        }
+        // We only asked for the list of translation items to count up the
+        // drop glue, so there's no need to pass it on.
+        if drop_glue_log.is_some() && line.starts_with("TRANS_ITEM ") {
+            if line.starts_with("TRANS_ITEM drop-glue ") {
+                drop_glue += 1;
+            } else if line.starts_with("TRANS_ITEM drop-glue-contents ") {
+                drop_glue_contents += 1;
+            }
+            continue
+        }
+        t!(writeln!(out, "{}", line));
+    }
+    if let Some(log) = drop_glue_log {
+        append_line(log, &format!("{}: {} drop-glue, {} drop-glue-contents",
+                                  krate, drop_glue, drop_glue_contents));
+    }
+}
+
+/// A reader which copies everything read from `inner` into `log`, which may
+/// be shared with other streams.
+struct Tee<R> {
+    inner: R,
+    log: Option<Arc<Mutex<File>>>,
+}
+
+impl<R: Read> Read for Tee<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.inner.read(buf) {
+            Ok(n) => n,
+            Err(e) => return Err(e),
+        };
+        if let Some(ref log) = self.log {
+            t!(log.lock().unwrap().write_all(&buf[..n]));
+        }
+        return Ok(n)
+    }
+}
+
+/// Forwards the compiler's JSON diagnostics to `err`, either as-is if `json`
+/// is set or rendered for humans otherwise, and adds them to the SARIF log at
+/// `sarif` as a run of their own.
+///
+/// If there's a `json_out` file the JSON is written there as it comes, and
+/// just the rendered diagnostics are forwarded.
+///
+/// With `dedup` set diagnostics are held back until the compiler's finished,
+/// and then each distinct message and primary span is emitted just once,
+/// with a count of how many times it came up.
+fn forward_diagnostics<R: BufRead, W: Write>(stderr: R, mut err: W,
+                                             sarif: Option<&Path>, json: bool,
+                                             json_out: Option<&Path>,
+                                             dedup: bool) {
+    let mut json_out = json_out.map(|path| t!(File::create(path)));
+    let mut seen: Vec<(String, Option<Json>, Json, usize)> = Vec::new();
+    let mut results = Vec::new();
+    for line in stderr.lines() {
+        let line = t!(line);
+        let diag = match Json::from_str(&line) {
+            Ok(diag) => diag,
+            Err(..) => {
+                t!(writeln!(err, "{}", line));
+                continue
+            }
+        };
+        if sarif.is_some() {
+            results.push(sarif_result(&diag));
+        }
+        if let Some(ref mut out) = json_out {
+            t!(writeln!(out, "{}", line));
+        }
+        if dedup {
+            let message = str_field(&diag, "message").to_string();
+            let span = primary_span(&diag).cloned();
+            match seen.iter().position(|s| s.0 == message && s.1 == span) {
+                Some(i) => seen[i].3 += 1,
+                None => seen.push((message, span, diag, 1)),
+            }
+        } else if json {
+            t!(writeln!(err, "{}", line));
+        } else {
+            t!(write!(err, "{}", render(&diag)));
+        }
+    }
+
+    if let Some(sarif) = sarif {
+        add_sarif_run(sarif, results);
+    }
+
+    for (_, _, diag, count) in seen {
+        let diag = if count > 1 {
+            count_diagnostic(diag, count)
+        } else {
+            diag
+        };
+        if json {
+            t!(writeln!(err, "{}", diag));
+        } else {
+            t!(write!(err, "{}", render(&diag)));
+        }
+    }
+}
+
+/// Notes on a diagnostic that it was emitted `count` times, in both its
+/// message and the first line of the compiler's rendering of it.
+fn count_diagnostic(diag: Json, count: usize) -> Json {
+    let suffix = format!(" (emitted {} times)", count);
+    let mut diag = match diag {
+        Json::Object(diag) => diag,
+        diag => return diag,
+    };
+    if let Some(&mut Json::String(ref mut message)) = diag.get_mut("message") {
+        message.push_str(&suffix);
+    }
+    if let Some(&mut Json::String(ref mut rendered)) = diag.get_mut("rendered") {
+        let end = rendered.find('\n').unwrap_or(rendered.len());
+        *rendered = format!("{}{}{}", &rendered[..end], suffix, &rendered[end..]);
+    }
+    return Json::Object(diag)
+}
+
+/// Adds a run of the compiler with `results` to the SARIF log at `path`,
+/// creating the log if need be. Parallel invocations all add their runs to
+/// the same log, so it's locked while we update it.
+fn add_sarif_run(path: &Path, results: Vec<Json>) {
+    let _lock = Lock::new(path);
+    let mut log = if path.exists() {
+        let contents = String::from_utf8(read_bytes(path)).unwrap();
+        match Json::from_str(&contents) {
+            Ok(Json::Object(log)) => log,
+            _ => panic!("{} isn't a SARIF log", path.display()),
+        }
+    } else {
+        let mut log = BTreeMap::new();
+        log.insert("version".to_string(), "2.1.0".to_json());
+        log.insert("$schema".to_string(), SARIF_SCHEMA.to_json());
+        log.insert("runs".to_string(), Json::Array(Vec::new()));
+        log
+    };
+    let driver = object(&[("name", "rustc".to_json()),
+                          ("informationUri", "https://www.rust-lang.org/".to_json())]);
+    let run = object(&[("tool", object(&[("driver", driver)])),
+                       ("results", results.to_json())]);
+    match log.get_mut("runs") {
+        Some(&mut Json::Array(ref mut runs)) => runs.push(run),
+        _ => panic!("{} has no runs", path.display()),
+    }
+    t!(t!(File::create(path)).write_all(Json::Object(log).to_string().as_bytes()));
+}
+
+const SARIF_SCHEMA: &'static str =
+    "https://json.schemastore.org/sarif-2.1.0.json";
+
+/// Converts a compiler diagnostic into a SARIF `result` object.
+fn sarif_result(diag: &Json) -> Json {
+    let level = match str_field(diag, "level") {
+        "warning" => "warning",
+        "note" | "help" => "note",
+        _ => "error",
+    };
+    let rule = diag.find("code").and_then(|c| c.find("code"))
+                   .and_then(|c| c.as_string())
+                   .unwrap_or("rustc");
+    let locations = primary_span(diag).map(|span| {
+        let region = object(&[
+            ("startLine", field(span, "line_start")),
+            ("startColumn", field(span, "column_start")),
+            ("endLine", field(span, "line_end")),
+            ("endColumn", field(span, "column_end")),
+        ]);
+        let artifact = object(&[("uri", str_field(span, "file_name").to_json())]);
+        let physical = object(&[("artifactLocation", artifact),
+                                ("region", region)]);
+        object(&[("physicalLocation", physical)])
+    });
+    object(&[
+        ("ruleId", rule.to_json()),
+        ("level", level.to_json()),
+        ("message", object(&[("text", str_field(diag, "message").to_json())])),
+        ("locations", locations.into_iter().collect::<Vec<_>>().to_json()),
+    ])
+}
+
+/// Renders a compiler diagnostic for humans, preferring the compiler's own
+/// rendering if it gave us one.
+fn render(diag: &Json) -> String {
+    if let Some(s) = diag.find("rendered").and_then(|r| r.as_string()) {
+        return s.to_string()
+    }
+    let mut s = format!("{}: {}\n", str_field(diag, "level"),
+                        str_field(diag, "message"));
+    if let Some(span) = primary_span(diag) {
+        s.push_str(&format!("  --> {}:{}:{}\n", str_field(span, "file_name"),
+                            field(span, "line_start"),
+                            field(span, "column_start")));
+    }
+    let children = diag.find("children").and_then(|c| c.as_array());
+    for child in children.map(|c| &c[..]).unwrap_or(&[]) {
+        s.push_str(&format!("  = {}: {}\n", str_field(child, "level"),
+                            str_field(child, "message")));
+    }
+    return s
+}
+
+fn primary_span(diag: &Json) -> Option<&Json> {
+    diag.find("spans").and_then(|s| s.as_array()).and_then(|spans| {
+        spans.iter().find(|s| {
+            s.find("is_primary").and_then(|p| p.as_boolean()) == Some(true)
+        })
+    })
+}
+
+fn field(json: &Json, field: &str) -> Json {
+    json.find(field).cloned().unwrap_or(Json::Null)
+}
+
+fn str_field<'a>(json: &'a Json, field: &str) -> &'a str {
+    json.find(field).and_then(|f| f.as_string()).unwrap_or("")
+}
+
+fn object(fields: &[(&str, Json)]) -> Json {
+    Json::Object(fields.iter().map(|&(k, ref v)| (k.to_string(), v.clone()))
+                       .collect::<BTreeMap<_, _>>())
+}
+
+/// Appends `line` to the file at `path`, creating it if need be.
+///
+/// The line is written out with a single call to `write` on a file opened in
+/// append mode so concurrent invocations of the shim don't interleave.
+fn append_line(path: &Path, line: &str) {
+    let mut f = t!(OpenOptions::new().create(true).append(true).open(path));
+    t!(f.write_all(format!("{}\n", line).as_bytes()));
+}
+
+/// Returns the value of the last `-C <name>=<value>` in `args`, accepting
+/// both the `-C name=value` and `-Cname=value` spellings.
+fn codegen_opt<'a, I>(args: I, name: &str) -> Option<String>
+    where I: IntoIterator<Item = &'a OsString>
+{
+    option(args, "-C", name)
+}
+
+/// Like `codegen_opt`, but for `-Z` debugging options.
+fn debugging_opt<'a, I>(args: I, name: &str) -> Option<String>
+    where I: IntoIterator<Item = &'a OsString>
+{
+    option(args, "-Z", name)
+}
+
+fn option<'a, I>(args: I, flag: &str, name: &str) -> Option<String>
+    where I: IntoIterator<Item = &'a OsString>
+{
+    let mut ret = None;
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        // Values that aren't valid UTF-8 are converted lossily rather than
+        // being skipped over, so we at least know the option was passed.
+        let arg = arg.to_string_lossy();
+        let opt = if arg == flag {
+            match args.next() {
+                Some(opt) => opt.to_string_lossy().into_owned(),
+                None => continue,
+            }
+        } else if arg.starts_with(flag) {
+            arg[flag.len()..].to_string()
+        } else {
+            continue
+        };
+        let mut parts = opt.splitn(2, '=');
+        if parts.next() == Some(name) {
+            ret = parts.next().map(|s| s.to_string());
+        }
+    }
+    return ret
+}
+
+/// Removes repeated arguments from a list of linker arguments, keeping the
+/// first occurrence of each in its original position.
+fn dedup_link_args(args: &[String]) -> Vec<String> {
+    let mut seen = HashSet::new();
+    args.iter().filter(|a| seen.insert(a.to_string())).cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::ffi::OsString;
+    use std::fs::{self, File};
+    use std::io::{self, Read, Write};
+    use std::path::{Path, PathBuf};
+    use std::process::Command;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant, UNIX_EPOCH};
+
+    use rustc_serialize::json::Json;
+
+    use super::{Env, Invocation, Lock, configure, dedup_link_args, run};
+    use super::{forward_diagnostics, is_stale, profile, scale_cgus, sha256};
+    use super::slow_compile;
+
+    fn env(vars: &[(&str, &str)]) -> Env {
+        let mut env = Env::with_vars(vars.iter().map(|&(k, v)| {
+            (k.to_string(), OsString::from(v))
+        }).collect());
+        for &(k, v) in [("RUSTC_REAL", "rustc-real"),
+                        ("RUSTC_SNAPSHOT", "rustc-snapshot"),
+                        ("RUSTC_SYSROOT", "sysroot"),
+                        ("RUSTC_STAGE", "1")].iter() {
+            env.vars.entry(k.to_string()).or_insert(OsString::from(v));
+        }
+        return env
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    fn tmpdir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("rustc-shim-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        return dir
+    }
+
+    /// Writes out a shell script to stand in for the real compiler.
+    #[cfg(unix)]
+    fn mock(dir: &Path, body: &str) -> String {
+        use std::os::unix::prelude::*;
+
+        let path = dir.join("rustc");
+        write(&path, &format!("#!/bin/sh\n{}\n", body));
+        let mut perms = fs::metadata(&path).unwrap().permissions();
+        perms.set_mode(0o755);
+        fs::set_permissions(&path, perms).unwrap();
+        return path.to_str().unwrap().to_string()
+    }
+
+    fn write(path: &Path, contents: &str) {
+        File::create(path).unwrap().write_all(contents.as_bytes()).unwrap();
+    }
+
+    fn read(path: &Path) -> String {
+        let mut s = String::new();
+        File::open(path).unwrap().read_to_string(&mut s).unwrap();
+        return s
+    }
+
+    fn has_pair(cmd: &Invocation, a: &str, b: &str) -> bool {
+        cmd.added.windows(2).any(|w| &*w[0] == a && &*w[1] == b)
+    }
+
+    #[test]
+    fn link_args_dedup() {
+        let list = ["-Wl,-rpath,$ORIGIN/../lib", "-lfoo", "-lbar",
+                    "-Wl,-rpath,$ORIGIN/../lib", "-lfoo", "-lbaz"];
+        let list = list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(dedup_link_args(&list),
+                   ["-Wl,-rpath,$ORIGIN/../lib", "-lfoo", "-lbar", "-lbaz"]);
+    }
+
+    #[test]
+    fn link_args_bundle() {
+        let target = args(&["--target", "x86_64-unknown-linux-gnu"]);
+        let cmd = configure(&target, &env(&[("RUSTC_RPATH", "true"),
+                                            ("RUSTC_LINK_ARGS_DEDUP", "1")]));
+        assert!(has_pair(&cmd, "-C", "link-args=-Wl,-rpath,$ORIGIN/../lib"));
+    }
+
+    #[test]
+    fn panic_abort_tests() {
+        let env = env(&[("RUSTC_PANIC_ABORT_TESTS", "1")]);
+        let test = args(&["--test", "-C", "panic=abort",
+                          "--target", "x86_64-unknown-linux-gnu"]);
+        let cmd = configure(&test, &env);
+        assert!(has_pair(&cmd, "-Z", "panic-abort-tests"));
+
+        let lib = args(&["-C", "panic=abort",
+                         "--target", "x86_64-unknown-linux-gnu"]);
+        let cmd = configure(&lib, &env);
+        assert!(!has_pair(&cmd, "-Z", "panic-abort-tests"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn fuel_log() {
+        let dir = tmpdir("fuel-log");
+        let log = dir.join("fuel.log");
+        let rustc = mock(&dir, "echo 'Fuel used by foo: 42'");
+        let env = env(&[("RUSTC_REAL", &rustc),
+                        ("RUSTC_FUEL", "100"),
+                        ("RUSTC_FUEL_LOG", log.to_str().unwrap())]);
+        let cmd = configure(&args(&["--crate-name", "foo",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-Z", "fuel=foo=100"));
+        assert!(has_pair(&cmd, "-Z", "print-fuel=foo"));
+        assert_eq!(run(&cmd), 0);
+        assert_eq!(read(&log), "foo: 42\n");
+    }
+
+    #[test]
+    fn native_libs() {
+        let env = env(&[("RUSTC_NATIVE_LIBS",
+                         "static:+whole-archive=foo\n\ndylib=bar\n")]);
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-l", "static:+whole-archive=foo"));
+        assert!(has_pair(&cmd, "-l", "dylib=bar"));
+        assert_eq!(cmd.added.iter().filter(|a| &***a == "-l").count(), 2);
+    }
+
+    #[test]
+    fn out_dir_per_stage() {
+        let dir = tmpdir("out-dir-per-stage");
+        let out = dir.to_str().unwrap();
+        let file = dir.join("foo.o");
+        let env = env(&[("RUSTC_OUT_DIR_PER_STAGE", "1"), ("RUSTC_STAGE", "2")]);
+        let cmd = configure(&args(&["--out-dir", out,
+                                    "-o", file.to_str().unwrap(),
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert_eq!(cmd.args[1], dir.join("stage2").into_os_string());
+        assert_eq!(cmd.args[3], dir.join("stage2/foo.o").into_os_string());
+        assert!(dir.join("stage2").is_dir());
+
+        // Dependencies are looked for in the same stage's directory.
+        let rlib = dir.join("libfoo.rlib");
+        let cmd = configure(&args(&["--crate-name", "bar",
+                                    "--out-dir", out,
+                                    "-L", &format!("dependency={}", out),
+                                    "-L", "native=/usr/lib",
+                                    "--extern", &format!("foo={}", rlib.display()),
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        let mut dep = OsString::from("dependency=");
+        dep.push(dir.join("stage2"));
+        let mut foo = OsString::from("foo=");
+        foo.push(dir.join("stage2/libfoo.rlib"));
+        assert_eq!(cmd.args[3], dir.join("stage2").into_os_string());
+        assert_eq!(cmd.args[5], dep);
+        assert_eq!(cmd.args[7], "native=/usr/lib");
+        assert_eq!(cmd.args[9], foo);
+    }
+
+    #[test]
+    fn default_visibility() {
+        let target = args(&["--target", "x86_64-unknown-linux-gnu"]);
+        for v in ["default", "hidden", "protected"].iter() {
+            let env = env(&[("RUSTC_DEFAULT_VISIBILITY", v)]);
+            let cmd = configure(&target, &env);
+            assert!(has_pair(&cmd, "-Z", &format!("default-visibility={}", v)));
+
+            let cmd = configure(&[], &env);
+            assert!(!cmd.added.iter().any(|a| &**a == "-Z"));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid RUSTC_DEFAULT_VISIBILITY")]
+    fn default_visibility_invalid() {
+        configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                  &env(&[("RUSTC_DEFAULT_VISIBILITY", "internal")]));
+    }
+
+    #[test]
+    fn wasm_link_args() {
+        let env = env(&[("RUSTC_WASM_LINK_ARGS",
+                         "--import-memory\n--export-memory")]);
+        let cmd = configure(&args(&["--target", "wasm32-unknown-unknown"]),
+                            &env);
+        assert!(has_pair(&cmd, "-C", "link-arg=--import-memory"));
+        assert!(has_pair(&cmd, "-C", "link-arg=--export-memory"));
+        assert!(cmd.notes.is_empty());
+
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(!has_pair(&cmd, "-C", "link-arg=--import-memory"));
+        assert_eq!(cmd.notes.len(), 1);
+    }
+
+    #[test]
+    fn mingw_crt_dir() {
+        let env = env(&[("RUSTC_MINGW_CRT_DIR", "/usr/x86_64-w64-mingw32/lib")]);
+        let cmd = configure(&args(&["--target", "x86_64-pc-windows-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-L", "native=/usr/x86_64-w64-mingw32/lib"));
+
+        for target in ["x86_64-pc-windows-msvc", "x86_64-unknown-linux-gnu"].iter() {
+            let cmd = configure(&args(&["--target", target]), &env);
+            assert!(!cmd.added.iter().any(|a| &**a == "-L"));
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn objcopy() {
+        let dir = tmpdir("objcopy");
+        let log = dir.join("objcopy.log");
+        let objcopy = dir.join("objcopy");
+        fs::create_dir(&objcopy).unwrap();
+        let objcopy = mock(&objcopy, &format!("echo \"$@\" > {}", log.display()));
+        let args = args(&["--crate-name", "std", "--crate-type", "dylib",
+                          "--out-dir", dir.to_str().unwrap(),
+                          "-C", "extra-filename=-abc",
+                          "--target", "x86_64-unknown-linux-gnu"]);
+        let vars = env(&[("RUSTC_REAL", "true"),
+                         ("RUSTC_OBJCOPY", &objcopy),
+                         ("RUSTC_OBJCOPY_ARGS", "--prefix-symbols=foo_")]);
+        assert_eq!(run(&configure(&args, &vars)), 0);
+        assert_eq!(read(&log), format!("--prefix-symbols=foo_ {}/libstd-abc.so\n",
+                                       dir.display()));
+
+        let vars = env(&[("RUSTC_REAL", "true"),
+                         ("RUSTC_OBJCOPY", "false"),
+                         ("RUSTC_OBJCOPY_ARGS", "--strip-debug")]);
+        assert_eq!(run(&configure(&args, &vars)), 1);
+    }
+
+    #[test]
+    fn sarif() {
+        let dir = tmpdir("sarif");
+        let sarif = dir.join("out.sarif");
+        let env = env(&[("RUSTC_SARIF_OUT", sarif.to_str().unwrap())]);
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "--error-format", "json"));
+        assert_eq!(cmd.sarif, Some(sarif.clone()));
+
+        let diag = r#"{"message":"mismatched types","code":{"code":"E0308",
+            "explanation":null},"level":"error","spans":[{"file_name":"lib.rs",
+            "byte_start":10,"byte_end":13,"line_start":2,"line_end":2,
+            "column_start":5,"column_end":8,"is_primary":true}],
+            "children":[],"rendered":null}"#.replace("\n", "");
+        forward_diagnostics(diag.as_bytes(), io::sink(), Some(&sarif), false,
+                            None, false);
+        forward_diagnostics(&b""[..], io::sink(), Some(&sarif), false, None,
+                            false);
+
+        let log = Json::from_str(&read(&sarif)).unwrap();
+        assert_eq!(log.find("version").unwrap().as_string(), Some("2.1.0"));
+        assert!(log.find("$schema").is_some());
+        let runs = log.find("runs").unwrap().as_array().unwrap();
+        assert_eq!(runs.len(), 2);
+        let driver = runs[0].find("tool").unwrap().find("driver").unwrap();
+        assert_eq!(driver.find("name").unwrap().as_string(), Some("rustc"));
+        let results = runs[1].find("results").unwrap().as_array().unwrap();
+        assert!(results.is_empty());
+
+        let results = runs[0].find("results").unwrap().as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        let result = &results[0];
+        assert_eq!(result.find("ruleId").unwrap().as_string(), Some("E0308"));
+        assert_eq!(result.find("level").unwrap().as_string(), Some("error"));
+        let message = result.find("message").unwrap();
+        assert_eq!(message.find("text").unwrap().as_string(),
+                   Some("mismatched types"));
+        let locations = result.find("locations").unwrap().as_array().unwrap();
+        let physical = locations[0].find("physicalLocation").unwrap();
+        let uri = physical.find("artifactLocation").unwrap().find("uri");
+        assert_eq!(uri.unwrap().as_string(), Some("lib.rs"));
+        let region = physical.find("region").unwrap();
+        assert_eq!(region.find("startLine").unwrap().as_u64(), Some(2));
+        assert_eq!(region.find("startColumn").unwrap().as_u64(), Some(5));
+    }
+
+    #[test]
+    fn crate_attr() {
+        let env = env(&[("RUSTC_CRATE_ATTR", "feature(foo)\nallow(bar)"),
+                        ("RUSTC_CRATE_ATTR_CORE", "feature(baz)")]);
+        let cmd = configure(&args(&["--crate-name", "core",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-Z", "crate-attr=feature(foo)"));
+        assert!(has_pair(&cmd, "-Z", "crate-attr=allow(bar)"));
+        assert!(has_pair(&cmd, "-Z", "crate-attr=feature(baz)"));
+
+        let cmd = configure(&args(&["--crate-name", "std",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-Z", "crate-attr=feature(foo)"));
+        assert!(!has_pair(&cmd, "-Z", "crate-attr=feature(baz)"));
+    }
+
+    #[test]
+    fn move_size_limit() {
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_MOVE_SIZE_LIMIT", "4096")]));
+        assert!(has_pair(&cmd, "-Z", "move-size-limit=4096"));
+    }
+
+    #[test]
+    #[should_panic(expected = "RUSTC_MOVE_SIZE_LIMIT must be a number")]
+    fn move_size_limit_invalid() {
+        configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                  &env(&[("RUSTC_MOVE_SIZE_LIMIT", "4k")]));
+    }
+
+    #[test]
+    fn force_dwarf_crates() {
+        let env = env(&[("RUSTC_FORCE_DWARF_CRATES", "core,std")]);
+        let krate = |name, target| {
+            configure(&args(&["--crate-name", name, "-C", "split-debuginfo=packed",
+                              "--target", target]), &env)
+        };
+        let cmd = krate("std", "x86_64-apple-darwin");
+        assert_eq!(cmd.codegen_opt("split-debuginfo"), Some("off".to_string()));
+        let cmd = krate("alloc", "x86_64-apple-darwin");
+        assert_eq!(cmd.codegen_opt("split-debuginfo"), Some("packed".to_string()));
+        let cmd = krate("std", "x86_64-unknown-linux-gnu");
+        assert_eq!(cmd.codegen_opt("split-debuginfo"), Some("packed".to_string()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn exit_status_file() {
+        let dir = tmpdir("exit-status-file");
+        let file = dir.join("status");
+        let env = env(&[("RUSTC_REAL", "true"),
+                        ("RUSTC_EXIT_STATUS_FILE", file.to_str().unwrap())]);
+        let cmd = configure(&args(&["--crate-name", "core",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert_eq!(run(&cmd), 0);
+        assert_eq!(read(&file), "crate=core target=x86_64-unknown-linux-gnu \
+                                 stage=1 exit=0\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reproducible_check() {
+        let dir = tmpdir("reproducible-check");
+        let out = dir.join("out");
+        fs::create_dir(&out).unwrap();
+        let compile = |contents| {
+            mock(&dir, &format!("
+                while [ $# -gt 0 ]; do
+                    if [ \"$1\" = --out-dir ]; then out=$2; fi
+                    shift
+                done
+                echo {} > $out/libfoo.rlib
+            ", contents))
+        };
+        let args = args(&["--crate-name", "foo", "--crate-type", "lib",
+                          "--out-dir", out.to_str().unwrap(),
+                          "--target", "x86_64-unknown-linux-gnu"]);
+
+        let rustc = compile("same");
+        let vars = env(&[("RUSTC_REAL", &rustc),
+                         ("RUSTC_REPRODUCIBLE_CHECK", "1")]);
+        assert_eq!(run(&configure(&args, &vars)), 0);
+
+        let rustc = compile("$$");
+        let vars = env(&[("RUSTC_REAL", &rustc),
+                         ("RUSTC_REPRODUCIBLE_CHECK", "1")]);
+        assert_eq!(run(&configure(&args, &vars)), 1);
+
+        let vars = env(&[("RUSTC_REAL", &rustc),
+                         ("RUSTC_REPRODUCIBLE_CHECK", "1"),
+                         ("RUSTC_REPRODUCIBLE_CRATES", "std")]);
+        assert_eq!(run(&configure(&args, &vars)), 0);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn pkg_config_libs() {
+        let dir = tmpdir("pkg-config-libs");
+        let calls = dir.join("calls");
+        let pkg_config = mock(&dir, &format!("
+            echo \"$@\" >> {}
+            echo '-L/opt/ssl/lib -lssl -lcrypto -pthread'
+        ", calls.display()));
+        let env = env(&[("PKG_CONFIG", &pkg_config),
+                        ("RUSTC_PKG_CONFIG_LIBS", "openssl zlib")]);
+        let args = args(&["--out-dir", dir.to_str().unwrap(),
+                          "--target", "x86_64-unknown-linux-gnu"]);
+        for _ in 0..2 {
+            let cmd = configure(&args, &env);
+            assert!(has_pair(&cmd, "-L", "native=/opt/ssl/lib"));
+            assert!(has_pair(&cmd, "-l", "ssl"));
+            assert!(has_pair(&cmd, "-l", "crypto"));
+            assert!(has_pair(&cmd, "-C", "link-arg=-pthread"));
+        }
+        assert_eq!(read(&calls), "--libs openssl zlib\n");
+    }
+
+    #[test]
+    fn extra_stage_cfg() {
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_EXTRA_STAGE_CFG", "next"),
+                                   ("RUSTC_STAGE", "1")]));
+        assert!(has_pair(&cmd, "--cfg", "stage1"));
+        assert!(has_pair(&cmd, "--cfg", "stage2"));
+
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_STAGE", "1")]));
+        assert!(!has_pair(&cmd, "--cfg", "stage2"));
+    }
+
+    #[test]
+    fn track_env_deps() {
+        let dir = tmpdir("track-env-deps");
+        let env = env(&[("RUSTC_TRACK_ENV_DEPS", "1"),
+                        ("RUSTC_CODEGEN_UNITS", "4"),
+                        ("RUSTC_UNRELATED", "1")]);
+        configure(&args(&["--crate-name", "core", "--out-dir",
+                          dir.to_str().unwrap(),
+                          "-C", "extra-filename=-abc",
+                          "--target", "x86_64-unknown-linux-gnu"]), &env);
+        let deps = read(&dir.join("core-abc.d.env"));
+        let deps = deps.lines().collect::<Vec<_>>();
+        assert!(deps.contains(&"RUSTC_CODEGEN_UNITS=4"));
+        assert!(deps.contains(&"RUSTC_REAL=rustc-real"));
+        assert!(deps.contains(&"RUSTC_STAGE=1"));
+        assert!(deps.contains(&"RUSTC_TRACK_ENV_DEPS=1"));
+        assert!(deps.contains(&"RUSTC_DEBUGINFO"));
+        assert!(!deps.iter().any(|d| d.starts_with("RUSTC_UNRELATED")));
+    }
+
+    #[test]
+    fn duplicate_flags() {
+        let args = args(&["-C", "opt-level=2", "--target", "x86_64-unknown-linux-gnu",
+                          "-Copt-level=3"]);
+        let cmd = configure(&args, &env(&[]));
+        assert_eq!(cmd.codegen_opt("opt-level"), Some("3".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "`-C opt-level` was passed to the compiler more than once")]
+    fn duplicate_flags_strict() {
+        let args = args(&["-C", "opt-level=2", "--target", "x86_64-unknown-linux-gnu",
+                          "-Copt-level=3"]);
+        configure(&args, &env(&[("RUSTC_ABORT_ON_DUPLICATE_FLAGS", "1")]));
+    }
+
+    #[test]
+    fn duplicate_flags_strict_overrides() {
+        let cmd = configure(&args(&["-C", "linker=clang",
+                                    "--crate-name", "core",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_ABORT_ON_DUPLICATE_FLAGS", "1"),
+                                   ("RUSTC_LINKER_WRAPPER", "/usr/bin/resign-link"),
+                                   ("RUSTC_DEBUGINFO_CRATES", "core"),
+                                   ("RUSTC_FLAGS", "-C debuginfo=0")]));
+        assert_eq!(cmd.codegen_opt("debuginfo"), Some("2".to_string()));
+        assert!(cmd.codegen_opt("linker") != Some("clang".to_string()));
+    }
+
+    #[test]
+    #[should_panic(expected = "`-C debuginfo` was passed to the compiler more than once")]
+    fn duplicate_flags_strict_user_flags() {
+        configure(&args(&["-C", "debuginfo=2", "--target", "x86_64-unknown-linux-gnu"]),
+                  &env(&[("RUSTC_ABORT_ON_DUPLICATE_FLAGS", "1"),
+                         ("RUSTC_FLAGS", "-C debuginfo=0")]));
+    }
+
+    #[test]
+    fn lock() {
+        let dir = tmpdir("lock");
+        let file = dir.join("shared");
+        let lock = Lock::new(&file);
+        let (tx, rx) = mpsc::channel();
+        let waiter = thread::spawn(move || {
+            let _lock = Lock::new(&file);
+            tx.send(()).unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(rx.try_recv().is_err());
+        drop(lock);
+        rx.recv().unwrap();
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn hash_salt() {
+        let args = args(&["-C", "metadata=abc123",
+                          "--target", "x86_64-unknown-linux-gnu"]);
+        let cmd = configure(&args, &env(&[("RUSTC_HASH_SALT", "sysroot2")]));
+        assert!(has_pair(&cmd, "-C", "metadata=sysroot2"));
+        assert_eq!(cmd.args[1], "metadata=abc123");
+
+        let cmd = configure(&[], &env(&[("RUSTC_HASH_SALT", "sysroot2")]));
+        assert!(!has_pair(&cmd, "-C", "metadata=sysroot2"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn drop_glue_stats() {
+        let dir = tmpdir("drop-glue-stats");
+        let log = dir.join("drop-glue.log");
+        let rustc = mock(&dir, "
+            echo 'TRANS_ITEM fn foo::bar[0]'
+            echo 'TRANS_ITEM drop-glue foo::Baz[0]'
+            echo 'TRANS_ITEM drop-glue foo::Quux[0]'
+            echo 'TRANS_ITEM drop-glue-contents foo::Quux[0]'
+        ");
+        let env = env(&[("RUSTC_REAL", &rustc),
+                        ("RUSTC_DROP_GLUE_STATS", log.to_str().unwrap())]);
+        for krate in ["foo", "bar"].iter() {
+            let cmd = configure(&args(&["--crate-name", krate,
+                                        "--target", "x86_64-unknown-linux-gnu"]),
+                                &env);
+            assert!(has_pair(&cmd, "-Z", "print-trans-items=lazy"));
+            assert_eq!(run(&cmd), 0);
+        }
+        assert_eq!(read(&log), "foo: 2 drop-glue, 1 drop-glue-contents\n\
+                                bar: 2 drop-glue, 1 drop-glue-contents\n");
+    }
+
+    #[test]
+    fn sysroot_dep_override() {
+        let env = env(&[("RUSTC_SYSROOT_DEP_OVERRIDE",
+                         "libc=/patched/liblibc.rlib")]);
+        let cmd = configure(&args(&["--crate-name", "std",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "--extern", "libc=/patched/liblibc.rlib"));
+        assert!(has_pair(&cmd, "-L", "dependency=/patched"));
+
+        let cmd = configure(&args(&["--crate-name", "rustc",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(!cmd.added.iter().any(|a| &**a == "--extern"));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid RUSTC_SYSROOT_DEP_OVERRIDE entry")]
+    fn sysroot_dep_override_invalid() {
+        configure(&args(&["--crate-name", "std",
+                          "--target", "x86_64-unknown-linux-gnu"]),
+                  &env(&[("RUSTC_SYSROOT_DEP_OVERRIDE", "/patched/liblibc.rlib")]));
+    }
+
+    #[test]
+    fn merge_functions() {
+        let target = args(&["--target", "x86_64-unknown-linux-gnu"]);
+        for mode in ["disabled", "trampolines", "aliases"].iter() {
+            let env = env(&[("RUSTC_MERGE_FUNCTIONS", mode)]);
+            let cmd = configure(&target, &env);
+            assert!(has_pair(&cmd, "-Z", &format!("merge-functions={}", mode)));
+
+            let cmd = configure(&[], &env);
+            assert!(!cmd.added.iter().any(|a| &**a == "-Z"));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid RUSTC_MERGE_FUNCTIONS `thunks`")]
+    fn merge_functions_invalid() {
+        configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                  &env(&[("RUSTC_MERGE_FUNCTIONS", "thunks")]));
+    }
+
+    #[test]
+    fn gc_sections() {
+        let vars = env(&[("RUSTC_RPATH", "true"), ("RUSTC_GC_SECTIONS", "true")]);
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &vars);
+        assert!(has_pair(&cmd, "-C", "link-args=-Wl,-rpath,$ORIGIN/../lib \
+                                      -Wl,--gc-sections"));
+
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_GC_SECTIONS", "false")]));
+        assert!(has_pair(&cmd, "-C", "link-args=-Wl,--no-gc-sections"));
+
+        for target in ["x86_64-apple-darwin", "x86_64-pc-windows-msvc"].iter() {
+            let cmd = configure(&args(&["--target", target]), &vars);
+            assert!(!cmd.added.iter().any(|a| a.to_str().unwrap()
+                                                .contains("gc-sections")));
+            assert_eq!(cmd.notes.len(), 1);
+        }
+    }
+
+    #[test]
+    fn target_modifiers() {
+        let dir = tmpdir("target-modifiers");
+        let sysroot = dir.to_str().unwrap();
+        let target = args(&["--target", "x86_64-unknown-linux-gnu"]);
+        let cmd = configure(&target, &env(&[("RUSTC_SYSROOT", sysroot),
+                                            ("RUSTC_TARGET_MODIFIERS",
+                                             "reg-struct-return,fixed-x18")]));
+        assert!(has_pair(&cmd, "-Z", "reg-struct-return"));
+        assert!(has_pair(&cmd, "-Z", "fixed-x18"));
+        assert!(cmd.warnings.is_empty());
+
+        let cmd = configure(&target, &env(&[("RUSTC_SYSROOT", sysroot),
+                                            ("RUSTC_TARGET_MODIFIERS",
+                                             "fixed-x18,reg-struct-return")]));
+        assert!(cmd.warnings.is_empty());
+
+        let cmd = configure(&target, &env(&[("RUSTC_SYSROOT", sysroot),
+                                            ("RUSTC_TARGET_MODIFIERS",
+                                             "fixed-x18")]));
+        assert_eq!(cmd.warnings.len(), 1);
+        let cmd = configure(&target, &env(&[("RUSTC_SYSROOT", sysroot)]));
+        assert!(cmd.warnings.is_empty());
+
+        // Each stage keeps its own record.
+        let cmd = configure(&target, &env(&[("RUSTC_SYSROOT", sysroot),
+                                            ("RUSTC_STAGE", "2"),
+                                            ("RUSTC_TARGET_MODIFIERS",
+                                             "fixed-x18")]));
+        assert!(cmd.warnings.is_empty());
+        assert_eq!(read(&dir.join("target-modifiers-stage2-\
+                                   x86_64-unknown-linux-gnu.stamp")),
+                   "fixed-x18");
+
+        // Custom targets given by path are recorded all the same.
+        let spec = dir.join("specs/x86_64-custom.json");
+        let target = args(&["--target", spec.to_str().unwrap()]);
+        for &(modifiers, warnings) in [("fixed-x18", 0), ("reg-struct-return", 1)].iter() {
+            let cmd = configure(&target, &env(&[("RUSTC_SYSROOT", sysroot),
+                                                ("RUSTC_TARGET_MODIFIERS",
+                                                 modifiers)]));
+            assert_eq!(cmd.warnings.len(), warnings);
+        }
+    }
+
+    #[test]
+    fn inline_mir() {
+        let target = args(&["--target", "x86_64-unknown-linux-gnu"]);
+        let cmd = configure(&target, &env(&[("RUSTC_INLINE_MIR", "true")]));
+        assert!(has_pair(&cmd, "-Z", "inline-mir=yes"));
+        let cmd = configure(&target, &env(&[("RUSTC_INLINE_MIR", "false")]));
+        assert!(has_pair(&cmd, "-Z", "inline-mir=no"));
+        let cmd = configure(&target, &env(&[]));
+        assert!(!cmd.added.iter().any(|a| a.to_str().unwrap()
+                                            .starts_with("inline-mir")));
+    }
+
+    #[test]
+    fn inline_mir_threshold() {
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_INLINE_MIR_THRESHOLD", "100")]));
+        assert!(has_pair(&cmd, "-Z", "inline-mir-threshold=100"));
+    }
+
+    #[test]
+    #[should_panic(expected = "RUSTC_INLINE_MIR_THRESHOLD must be a number")]
+    fn inline_mir_threshold_invalid() {
+        configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                  &env(&[("RUSTC_INLINE_MIR_THRESHOLD", "lots")]));
+    }
+
+    #[test]
+    fn profile_inference() {
+        assert_eq!(profile(&args(&["-g"])), "dev");
+        assert_eq!(profile(&args(&["-C", "opt-level=0", "-C", "debuginfo=2"])),
+                   "dev");
+        assert_eq!(profile(&args(&["-C", "opt-level=3"])), "release");
+        assert_eq!(profile(&args(&["-C", "opt-level=2", "-g"])), "release");
+        assert_eq!(profile(&args(&["-C", "debuginfo=0"])), "release");
+        assert_eq!(profile(&args(&[])), "release");
+    }
+
+    #[test]
+    fn profile_flags() {
+        let env = env(&[("RUSTC_FLAGS_DEV", "-Z time-passes"),
+                        ("RUSTC_FLAGS_RELEASE", "-C target-cpu=native")]);
+        let cmd = configure(&args(&["-g", "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-Z", "time-passes"));
+        assert!(!has_pair(&cmd, "-C", "target-cpu=native"));
+
+        let cmd = configure(&args(&["-C", "opt-level=3",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(!has_pair(&cmd, "-Z", "time-passes"));
+        assert!(has_pair(&cmd, "-C", "target-cpu=native"));
+    }
+
+    #[test]
+    fn provenance_lints() {
+        let env = env(&[("RUSTC_PROVENANCE_LINTS", "1")]);
+        let cmd = configure(&args(&["--crate-name", "core",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-Z", "crate-attr=feature(strict_provenance_lints)"));
+        assert!(has_pair(&cmd, "-W", "fuzzy_provenance_casts"));
+        assert!(has_pair(&cmd, "-W", "lossy_provenance_casts"));
+
+        let cmd = configure(&args(&["--crate-name", "rustc_trans",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(!cmd.added.iter().any(|a| &**a == "-W"));
+        assert!(!has_pair(&cmd, "-Z", "crate-attr=feature(strict_provenance_lints)"));
+
+        let cmd = configure(&args(&["--crate-name", "core"]), &env);
+        assert!(!cmd.added.iter().any(|a| &**a == "-W"));
+    }
+
+    #[test]
+    fn mono_stats() {
+        let dir = tmpdir("mono-stats").join("stats");
+        let env = env(&[("RUSTC_MONO_STATS_DIR", dir.to_str().unwrap()),
+                        ("RUSTC_MONO_STATS_FORMAT", "json")]);
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-Z", &format!("dump-mono-stats={}",
+                                              dir.display())));
+        assert!(has_pair(&cmd, "-Z", "dump-mono-stats-format=json"));
+        assert!(dir.is_dir());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid RUSTC_MONO_STATS_FORMAT `csv`")]
+    fn mono_stats_invalid_format() {
+        configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                  &env(&[("RUSTC_MONO_STATS_FORMAT", "csv")]));
+    }
+
+    #[test]
+    fn windows_use_lld() {
+        let env = env(&[("RUSTC_WINDOWS_USE_LLD", "1")]);
+        let cmd = configure(&args(&["--target", "x86_64-pc-windows-msvc"]), &env);
+        assert!(has_pair(&cmd, "-C", "linker=lld-link"));
+        assert!(has_pair(&cmd, "-C", "linker-flavor=lld-link"));
+        assert!(cmd.notes.is_empty());
+
+        let cmd = configure(&args(&["--target", "x86_64-pc-windows-gnu"]), &env);
+        assert!(!has_pair(&cmd, "-C", "linker=lld-link"));
+        assert!(!has_pair(&cmd, "-C", "linker-flavor=lld-link"));
+        assert_eq!(cmd.notes.len(), 1);
+    }
+
+    #[test]
+    fn sanitizer_recover() {
+        let env = env(&[("RUSTC_SANITIZER_RECOVER", "address"),
+                        ("RUSTC_FLAGS", "-Z sanitizer=address"),
+                        ("ASAN_OPTIONS", "detect_leaks=0")]);
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-Z", "sanitizer-recover=address"));
+        assert!(cmd.env.contains(&(OsString::from("ASAN_OPTIONS"),
+                                   OsString::from("detect_leaks=0"))));
+        assert!(!cmd.env.iter().any(|&(ref k, _)| k == "TSAN_OPTIONS"));
+    }
+
+    #[test]
+    #[should_panic(expected = "no sanitizer is enabled")]
+    fn sanitizer_recover_without_sanitizer() {
+        configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                  &env(&[("RUSTC_SANITIZER_RECOVER", "address")]));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn build_timeout() {
+        let dir = tmpdir("build-timeout");
+        let file = dir.join("status");
+        let rustc = mock(&dir, "exec sleep 10");
+        let env = env(&[("RUSTC_REAL", &rustc),
+                        ("RUSTC_BUILD_TIMEOUT", "1"),
+                        ("RUSTC_EXIT_STATUS_FILE", file.to_str().unwrap())]);
+        let cmd = configure(&args(&["--crate-name", "slow",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        let start = Instant::now();
+        assert_eq!(run(&cmd), 124);
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(read(&file), "crate=slow target=x86_64-unknown-linux-gnu \
+                                 stage=1 exit=timeout\n");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn build_timeout_kills_process_tree() {
+        let dir = tmpdir("build-timeout-tree");
+        let pid = dir.join("pid");
+        let rustc = mock(&dir, &format!("sleep 30 & echo $! > {}; wait",
+                                        pid.display()));
+        let cmd = configure(&args(&["--crate-name", "slow",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_REAL", &rustc),
+                                   ("RUSTC_BUILD_TIMEOUT", "1")]));
+        assert_eq!(run(&cmd), 124);
+
+        // Once its parent is gone the grandchild may linger as a zombie
+        // until it's reaped, which is as dead as we need it to be.
+        let pid = read(&pid);
+        let start = Instant::now();
+        loop {
+            let out = Command::new("ps").args(&["-o", "stat=", "-p", pid.trim()])
+                                        .output().unwrap();
+            let stat = String::from_utf8(out.stdout).unwrap();
+            if stat.trim().is_empty() || stat.trim().starts_with("Z") {
+                break
+            }
+            assert!(start.elapsed() < Duration::from_secs(5),
+                    "grandchild {} survived the timeout", pid.trim());
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    #[test]
+    fn extra_sysroot() {
+        let dir = tmpdir("extra-sysroot");
+        let lib = dir.join("lib/rustlib/x86_64-unknown-linux-gnu/lib");
+        fs::create_dir_all(&lib).unwrap();
+        let env = env(&[("RUSTC_EXTRA_SYSROOT", dir.to_str().unwrap())]);
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "--sysroot", "sysroot"));
+        assert!(has_pair(&cmd, "-L", &format!("dependency={}", lib.display())));
+    }
+
+    #[test]
+    #[should_panic(expected = "has no libraries for `x86_64-unknown-linux-gnu`")]
+    fn extra_sysroot_missing() {
+        let dir = tmpdir("extra-sysroot-missing");
+        configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                  &env(&[("RUSTC_EXTRA_SYSROOT", dir.to_str().unwrap())]));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn canonicalize_paths() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tmpdir("canonicalize-paths");
+        let real = dir.join("real");
+        let link = dir.join("link");
+        fs::create_dir(&real).unwrap();
+        symlink(&real, &link).unwrap();
+        let real = fs::canonicalize(&real).unwrap();
+
+        let env = env(&[("RUSTC_CANONICALIZE_PATHS", "1")]);
+        let cmd = configure(&args(&["--out-dir", link.to_str().unwrap(),
+                                    "-L", &format!("dependency={}", link.display()),
+                                    "-L", "native=/does/not/exist",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert_eq!(cmd.args[1], real.clone().into_os_string());
+        assert_eq!(cmd.args[3], OsString::from(format!("dependency={}",
+                                                       real.display())));
+        assert_eq!(cmd.args[5], "native=/does/not/exist");
+    }
+
+    #[test]
+    fn emit_obj_dir() {
+        let dir = tmpdir("emit-obj-dir").join("objs");
+        let env = env(&[("RUSTC_EMIT_OBJ_DIR", dir.to_str().unwrap())]);
+        let cmd = configure(&args(&["--crate-name", "core",
+                                    "--emit=dep-info,link",
+                                    "-C", "extra-filename=-abc",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        let emit = format!("--emit=obj={}", dir.join("core-abc.o").display());
+        assert!(cmd.added.iter().any(|a| **a == emit[..]));
+        assert_eq!(cmd.args[2], "--emit=dep-info,link");
+        assert!(dir.is_dir());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn merged_dep_info() {
+        let dir = tmpdir("merged-dep-info");
+        let merged = dir.join("merged.d");
+        let rustc = mock(&dir, "
+            while [ $# -gt 0 ]; do
+                case $1 in
+                    --out-dir) out=$2 ;;
+                    --crate-name) name=$2 ;;
+                esac
+                shift
+            done
+            printf '%s: src/%s.rs \\\n src/common\\ dir/mod.rs\n\n' \
+                $out/lib$name.rlib $name > $out/$name.d
+            printf 'src/%s.rs:\nsrc/common\\ dir/mod.rs:\n' $name >> $out/$name.d
+        ");
+        let env = env(&[("RUSTC_REAL", &rustc),
+                        ("RUSTC_MERGED_DEP_INFO", merged.to_str().unwrap())]);
+        for krate in ["foo", "bar", "foo"].iter() {
+            let cmd = configure(&args(&["--crate-name", krate,
+                                        "--out-dir", dir.to_str().unwrap(),
+                                        "--target", "x86_64-unknown-linux-gnu"]),
+                                &env);
+            assert_eq!(run(&cmd), 0);
+        }
+        let d = dir.display();
+        assert_eq!(read(&merged), format!("\
+{d}/libfoo.rlib: src/foo.rs src/common\\ dir/mod.rs
+
+src/foo.rs:
+
+src/common\\ dir/mod.rs:
+
+{d}/libbar.rlib: src/bar.rs src/common\\ dir/mod.rs
+
+src/bar.rs:
+
+", d = d));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn stats_json() {
+        let dir = tmpdir("stats-json");
+        let stats = dir.join("stats.json");
+        let env = env(&[("RUSTC_REAL", "true"),
+                        ("RUSTC_CODEGEN_UNITS", "4"),
+                        ("RUSTC_STATS_JSON", stats.to_str().unwrap())]);
+        let cmd = configure(&args(&["--crate-name", "core",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert_eq!(run(&cmd), 0);
+
+        let stats = read(&stats);
+        assert_eq!(stats.lines().count(), 1);
+        let stats = Json::from_str(&stats).unwrap();
+        let field = |name| stats.find(name).unwrap();
+        assert_eq!(field("crate").as_string(), Some("core"));
+        assert_eq!(field("stage").as_string(), Some("1"));
+        assert_eq!(field("target").as_string(), Some("x86_64-unknown-linux-gnu"));
+        assert_eq!(field("compiler").as_string(), Some("true"));
+        assert_eq!(field("exit_code").as_i64(), Some(0));
+        let added = field("added_flags").as_array().unwrap();
+        let added = added.iter().map(|a| a.as_string().unwrap()).collect::<Vec<_>>();
+        assert_eq!(added, cmd.added.iter().map(|a| a.to_str().unwrap())
+                                       .collect::<Vec<_>>());
+        assert!(added.contains(&"codegen-units=4"));
+    }
+
+    #[test]
+    fn no_stage_cfg() {
+        let target = args(&["--target", "x86_64-unknown-linux-gnu"]);
+        let cmd = configure(&target, &env(&[("RUSTC_NO_STAGE_CFG", "1"),
+                                            ("RUSTC_EXTRA_STAGE_CFG", "next")]));
+        assert!(!cmd.added.iter().any(|a| &**a == "--cfg"));
+
+        let cmd = configure(&target, &env(&[]));
+        assert!(has_pair(&cmd, "--cfg", "stage1"));
+    }
+
+    #[test]
+    fn linker_wrapper() {
+        let env = env(&[("RUSTC_LINKER_WRAPPER", "/usr/bin/resign-link")]);
+        let cmd = configure(&args(&["-C", "linker=clang",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert_eq!(cmd.codegen_opt("linker"), Some("/usr/bin/resign-link".to_string()));
+        assert!(cmd.env.contains(&(OsString::from("RUSTC_REAL_LINKER"),
+                                   OsString::from("clang"))));
+
+        let cmd = configure(&args(&["--target", "x86_64-pc-windows-msvc"]), &env);
+        assert!(cmd.env.contains(&(OsString::from("RUSTC_REAL_LINKER"),
+                                   OsString::from("link.exe"))));
+    }
+
+    #[test]
+    fn overflow_checks_std() {
+        let env = env(&[("RUSTC_OVERFLOW_CHECKS_STD", "1"),
+                        ("RUSTC_FLAGS", "-C overflow-checks=n")]);
+        let krate = |name| {
+            configure(&args(&["--crate-name", name,
+                              "--target", "x86_64-unknown-linux-gnu"]), &env)
+        };
+        assert_eq!(krate("std").codegen_opt("overflow-checks"),
+                   Some("y".to_string()));
+        assert_eq!(krate("collections").codegen_opt("overflow-checks"),
+                   Some("y".to_string()));
+        assert_eq!(krate("rustc_driver").codegen_opt("overflow-checks"),
+                   Some("n".to_string()));
+    }
+
+    #[cfg(unix)]
+    fn target_features_env(name: &str, features: &str) -> Env {
+        let dir = tmpdir(name);
+        let rustc = mock(&dir, "cat <<EOF
+Features supported by rustc for this target:
+    avx                 - Enable AVX instructions.
+    sse4.2              - Enable SSE 4.2 instructions.
+
+Use +feature to enable a feature, or -feature to disable it.
+EOF");
+        env(&[("RUSTC_REAL", &rustc),
+              ("RUSTC_SYSROOT", dir.to_str().unwrap()),
+              ("RUSTC_TARGET_FEATURES", features),
+              ("RUSTC_VALIDATE_TARGET_FEATURES", "1")])
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn target_features() {
+        let env = target_features_env("target-features", "+sse4.2,-avx");
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-C", "target-feature=+sse4.2,-avx"));
+
+        let dir = env::temp_dir().join("rustc-shim-target-features");
+        let stamp = dir.join("target-features-x86_64-unknown-linux-gnu.stamp");
+        assert!(read(&stamp).contains("sse4.2"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // Custom targets given by path get a stamp named after the spec.
+        let spec = dir.join("specs/thumbv7em-custom.json");
+        let cmd = configure(&args(&["--target", spec.to_str().unwrap()]), &env);
+        assert!(has_pair(&cmd, "-C", "target-feature=+sse4.2,-avx"));
+        let stamps = fs::read_dir(&dir).unwrap().map(|e| {
+            e.unwrap().file_name().into_string().unwrap()
+        }).filter(|name| name.starts_with("target-features-thumbv7em-custom-"))
+          .collect::<Vec<_>>();
+        assert_eq!(stamps.len(), 1);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    #[should_panic(expected = "unknown target feature `avx512`")]
+    fn target_features_invalid() {
+        let env = target_features_env("target-features-invalid", "+avx512");
+        configure(&args(&["--target", "x86_64-unknown-linux-gnu"]), &env);
+    }
+
+    #[test]
+    fn emit_stack_sizes() {
+        let vars = env(&[("RUSTC_EMIT_STACK_SIZES", "1")]);
+        let cmd = configure(&args(&["--target", "thumbv7em-none-eabihf"]), &vars);
+        assert!(has_pair(&cmd, "-Z", "emit-stack-sizes"));
+        assert!(cmd.notes.is_empty());
+
+        let cmd = configure(&args(&["--target", "x86_64-apple-darwin"]), &vars);
+        assert!(!has_pair(&cmd, "-Z", "emit-stack-sizes"));
+        assert_eq!(cmd.notes.len(), 1);
+    }
+
+    #[test]
+    fn dedup_diagnostics() {
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_DEDUP_DIAGNOSTICS", "1")]));
+        assert!(has_pair(&cmd, "--error-format", "json"));
+        assert!(cmd.dedup_diagnostics);
+        assert_eq!(cmd.sarif, None);
+
+        let cmd = configure(&args(&["--error-format=json",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_DEDUP_DIAGNOSTICS", "1")]));
+        assert!(!cmd.added.iter().any(|a| a == "--error-format"));
+        assert!(cmd.forward_json);
+
+        let diag = |message: &str, line: u32| {
+            format!(r#"{{"message":"{}","level":"error","spans":[{{
+                "file_name":"lib.rs","line_start":{},"line_end":{},
+                "column_start":5,"column_end":8,"is_primary":true}}],
+                "children":[],"rendered":null}}"#, message, line, line)
+                .replace("\n", "")
+        };
+        let stderr = [diag("overflow evaluating", 2), diag("overflow evaluating", 2),
+                      diag("overflow evaluating", 2), diag("overflow evaluating", 7),
+                      diag("mismatched types", 2)].join("\n");
+        let mut out = Vec::new();
+        forward_diagnostics(stderr.as_bytes(), &mut out, None, false, None, true);
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out, "error: overflow evaluating (emitted 3 times)\n  \
+                           --> lib.rs:2:5\n\
+                         error: overflow evaluating\n  --> lib.rs:7:5\n\
+                         error: mismatched types\n  --> lib.rs:2:5\n");
+    }
+
+    #[test]
+    fn json_with_rendered() {
+        let dir = tmpdir("json-with-rendered");
+        let vars = env(&[("RUSTC_JSON_WITH_RENDERED", "1")]);
+        let cmd = configure(&args(&["--crate-name", "foo",
+                                    "--error-format", "json",
+                                    "--out-dir", dir.to_str().unwrap()]),
+                            &vars);
+        assert!(cmd.added.iter().any(|a| a == "--json=diagnostic-rendered-ansi"));
+        assert!(cmd.forward_json);
+        let json_out = dir.join("foo.diagnostics.json");
+        assert_eq!(cmd.json_out, Some(json_out.clone()));
+
+        // Asking for JSON ourselves for another reason is enough, but then
+        // it's the rendering that's forwarded.
+        let cmd = configure(&args(&["--crate-name", "foo",
+                                    "--out-dir", dir.to_str().unwrap()]),
+                            &env(&[("RUSTC_JSON_WITH_RENDERED", "1"),
+                                   ("RUSTC_DEDUP_DIAGNOSTICS", "1")]));
+        assert!(cmd.json_forced);
+        assert!(!cmd.forward_json);
+        assert_eq!(cmd.json_out, Some(json_out.clone()));
+
+        let cmd = configure(&args(&["--crate-name", "foo",
+                                    "--out-dir", dir.to_str().unwrap()]),
+                            &vars);
+        assert_eq!(cmd.json_out, None);
+        assert_eq!(cmd.notes.len(), 1);
+
+        let diag = r#"{"message":"unused variable","level":"warning",
+            "spans":[],"children":[],
+            "rendered":"\u001b[33mwarning\u001b[0m: unused variable\n"}"#
+            .replace("\n            ", "");
+        let mut out = Vec::new();
+        forward_diagnostics(diag.as_bytes(), &mut out, None, false,
+                            Some(&json_out), false);
+        assert_eq!(String::from_utf8(out).unwrap(),
+                   "\u{1b}[33mwarning\u{1b}[0m: unused variable\n");
+        assert_eq!(read(&json_out), format!("{}\n", diag));
+    }
+
+    #[test]
+    fn stage0_only_crates() {
+        let vars = env(&[("RUSTC_STAGE0_ONLY_CRATES", "rustc_bitflags, alloc")]);
+        let cmd = configure(&args(&["--crate-name", "alloc",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &vars);
+        assert_eq!(cmd.rustc, "rustc-snapshot");
+        assert!(!cmd.added.iter().any(|a| a == "--sysroot"));
+
+        let cmd = configure(&args(&["--crate-name", "core",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &vars);
+        assert_eq!(cmd.rustc, "rustc-real");
+        assert!(has_pair(&cmd, "--sysroot", "sysroot"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn hash_manifest() {
+        assert_eq!(sha256(b""), "e3b0c44298fc1c149afbf4c8996fb924\
+                                 27ae41e4649b934ca495991b7852b855");
+        assert_eq!(sha256(&[b'a'; 1000]), "41edece42d63e8d9bf515a9ba6932e1c\
+                                           20cbc9f5a5d134645adb5db1b9737ea3");
+
+        let dir = tmpdir("hash-manifest");
+        let manifest = dir.join("manifest");
+        let rustc = mock(&dir, "
+            while [ $# -gt 0 ]; do
+                case $1 in
+                    --out-dir) out=$2 ;;
+                    --crate-name) name=$2 ;;
+                esac
+                shift
+            done
+            printf abc > $out/lib$name.rlib
+        ");
+        let env = env(&[("RUSTC_REAL", &rustc),
+                        ("RUSTC_HASH_MANIFEST", manifest.to_str().unwrap())]);
+        let cmd = configure(&args(&["--crate-name", "foo", "--crate-type", "lib",
+                                    "--out-dir", dir.to_str().unwrap(),
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert_eq!(run(&cmd), 0);
+        assert_eq!(read(&manifest),
+                   format!("ba7816bf8f01cfea414140de5dae2223\
+                            b00361a396177a9cb410ff61f20015ad  {}\n",
+                           dir.join("libfoo.rlib").display()));
+    }
+
+    #[test]
+    fn scale_cgus_by_size() {
+        assert_eq!(scale_cgus(0, 16, 1000), 16);
+        assert_eq!(scale_cgus(1000, 16, 1000), 16);
+        assert_eq!(scale_cgus(1001, 16, 1000), 8);
+        assert_eq!(scale_cgus(3000, 16, 1000), 4);
+        assert_eq!(scale_cgus(100000, 16, 1000), 1);
+        assert_eq!(scale_cgus(100000, 0, 1000), 1);
+    }
+
+    #[test]
+    fn adaptive_cgus() {
+        let dir = tmpdir("adaptive-cgus");
+        let src = dir.join("lib.rs");
+        write(&src, &vec!["// padding"; 800].join("\n"));
+        let args = args(&["--target", "x86_64-unknown-linux-gnu",
+                          src.to_str().unwrap()]);
+        let cmd = configure(&args, &env(&[("RUSTC_ADAPTIVE_CGUS", "1"),
+                                          ("RUSTC_ADAPTIVE_CGUS_THRESHOLD", "5000"),
+                                          ("RUSTC_CODEGEN_UNITS", "4")]));
+        assert!(has_pair(&cmd, "-C", "codegen-units=2"));
+
+        let cmd = configure(&args, &env(&[("RUSTC_ADAPTIVE_CGUS", "1")]));
+        assert_eq!(cmd.codegen_opt("codegen-units"), None);
+    }
+
+    #[test]
+    fn frame_pointers_std_only() {
+        let env = env(&[("RUSTC_FRAME_POINTERS_STD_ONLY", "1"),
+                        ("RUSTC_FLAGS", "-C force-frame-pointers=no")]);
+        let krate = |name| {
+            configure(&args(&["--crate-name", name,
+                              "--target", "x86_64-unknown-linux-gnu"]), &env)
+        };
+        assert_eq!(krate("std").codegen_opt("force-frame-pointers"),
+                   Some("yes".to_string()));
+        assert_eq!(krate("core").codegen_opt("force-frame-pointers"),
+                   Some("yes".to_string()));
+        assert_eq!(krate("rustc_driver").codegen_opt("force-frame-pointers"),
+                   Some("no".to_string()));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn extern_resolver() {
+        let dir = tmpdir("extern-resolver");
+        let resolver = mock(&dir, "
+            for e in \"$@\"; do
+                echo \"${e%%=*}=/cache/$(basename ${e#*=})\"
+            done
+        ");
+        let env = env(&[("RUSTC_EXTERN_RESOLVER", &resolver)]);
+        let cmd = configure(&args(&["--extern", "core=/remote/libcore.rlib",
+                                    "--extern", "proc_macro",
+                                    "--extern", "libc=/remote/liblibc.rlib"]),
+                            &env);
+        assert_eq!(cmd.args, args(&["--extern", "core=/cache/libcore.rlib",
+                                    "--extern", "proc_macro",
+                                    "--extern", "libc=/cache/liblibc.rlib"]));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    #[should_panic(expected = "invalid RUSTC_EXTERN_RESOLVER output `libc=`")]
+    fn extern_resolver_invalid() {
+        let dir = tmpdir("extern-resolver-invalid");
+        let resolver = mock(&dir, "echo libc=");
+        configure(&args(&["--extern", "libc=/remote/liblibc.rlib"]),
+                  &env(&[("RUSTC_EXTERN_RESOLVER", &resolver)]));
+    }
+
+    #[test]
+    fn stale_sysroot() {
+        let compiler = UNIX_EPOCH + Duration::from_secs(1000);
+        let old = UNIX_EPOCH + Duration::from_secs(500);
+        let new = UNIX_EPOCH + Duration::from_secs(1500);
+        assert!(is_stale(compiler, &[old]));
+        assert!(!is_stale(compiler, &[new]));
+        assert!(!is_stale(compiler, &[old, new]));
+        assert!(!is_stale(compiler, &[compiler]));
+        assert!(!is_stale(compiler, &[]));
+    }
+
+    #[test]
+    fn cfg_file() {
+        let dir = tmpdir("cfg-file");
+        let file = dir.join("cfgs");
+        write(&file, "# platform\nfoo\n\n  bar  \n#baz\nfeature=\"qux\"\n");
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_CFG_FILE", file.to_str().unwrap())]));
+        let cfgs = cmd.added.windows(2).filter(|w| &*w[0] == "--cfg")
+                            .map(|w| w[1].to_str().unwrap())
+                            .collect::<Vec<_>>();
+        assert_eq!(cfgs, ["stage1", "foo", "bar", "feature=\"qux\""]);
+    }
+
+    #[test]
+    fn split_metadata() {
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_SPLIT_METADATA", "1"),
+                                   ("RUSTC_RPATH", "true")]));
+        assert!(has_pair(&cmd, "-Z", "embed-metadata=no"));
+        assert!(cmd.added.iter().any(|a| a == "--emit=metadata"));
+        assert!(cmd.added.iter().any(|a| a == "-Cprefer-dynamic"));
+        assert!(has_pair(&cmd, "-C", "debug-assertions=n"));
+        assert!(has_pair(&cmd, "-C", "link-args=-Wl,-rpath,$ORIGIN/../lib"));
+
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[]));
+        assert!(!has_pair(&cmd, "-Z", "embed-metadata=no"));
+        assert!(!cmd.added.iter().any(|a| a == "--emit=metadata"));
+    }
+
+    #[test]
+    fn slow_compile_warning() {
+        let cmd = configure(&args(&[]), &env(&[("RUSTC_SLOW_COMPILE_SECS", "10")]));
+        assert_eq!(cmd.slow_compile, Some(Duration::from_secs(10)));
+        let cmd = configure(&args(&[]), &env(&[("RUSTC_SLOW_COMPILE_SECS", "0")]));
+        assert_eq!(cmd.slow_compile, None);
+
+        let threshold = Duration::from_secs(10);
+        assert_eq!(slow_compile("core", Duration::from_secs(3), threshold), None);
+        assert_eq!(slow_compile("core", threshold, threshold), None);
+        assert_eq!(slow_compile("core", Duration::from_millis(12345), threshold),
+                   Some("compiling `core` took 12.3s, more than the 10s allowed \
+                         by RUSTC_SLOW_COMPILE_SECS".to_string()));
+    }
+
+    #[test]
+    fn env_stamp_dir() {
+        let dir = tmpdir("env-stamp-dir");
+        let incremental = dir.join("incremental");
+        let core = incremental.join("core-1a2b3c");
+        let alloc = incremental.join("alloc-4d5e6f");
+        let args = args(&["--crate-name", "core",
+                          "-C", &format!("incremental={}", incremental.display()),
+                          "--target", "x86_64-unknown-linux-gnu"]);
+        fs::create_dir_all(&core).unwrap();
+        fs::create_dir_all(&alloc).unwrap();
+        let stamps = dir.join("stamps");
+        let compile = |units| {
+            configure(&args, &env(&[("RUSTC_ENV_STAMP_DIR", stamps.to_str().unwrap()),
+                                    ("RUSTC_CODEGEN_UNITS", units)]));
+        };
+
+        compile("4");
+        assert!(stamps.join("core.env-stamp").exists());
+        assert!(core.exists());
+        compile("4");
+        assert!(core.exists());
+        compile("8");
+        assert!(!core.exists());
+        assert!(alloc.exists());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn combined_log() {
+        let dir = tmpdir("combined-log");
+        let rustc = mock(&dir, "
+            echo to stdout
+            echo to stderr >&2
+            exit 3
+        ");
+        let env = env(&[("RUSTC_REAL", &rustc),
+                        ("RUSTC_COMBINED_LOG_DIR", dir.to_str().unwrap())]);
+        let cmd = configure(&args(&["--crate-name", "foo",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert_eq!(run(&cmd), 3);
+        let log = read(&dir.join("foo.log"));
+        assert!(log.contains("to stdout\n"));
+        assert!(log.contains("to stderr\n"));
+    }
+
+    #[test]
+    fn disable_incremental() {
+        let argv = args(&["--crate-name", "foo", "-C", "incremental=/tmp/inc",
+                          "-C", "opt-level=2", "-Cincremental=/tmp/inc",
+                          "--target", "x86_64-unknown-linux-gnu"]);
+        let cmd = configure(&argv, &env(&[("RUSTC_DISABLE_INCREMENTAL", "1")]));
+        assert_eq!(cmd.args, args(&["--crate-name", "foo", "-C", "opt-level=2",
+                                    "--target", "x86_64-unknown-linux-gnu"]));
+        assert_eq!(cmd.codegen_opt("incremental"), None);
+
+        let cmd = configure(&argv, &env(&[]));
+        assert_eq!(cmd.codegen_opt("incremental"), Some("/tmp/inc".to_string()));
+    }
+
+    fn target_spec(name: &str) -> PathBuf {
+        let dir = tmpdir(name);
+        let spec = dir.join("thumbv7m-custom.json");
+        write(&spec, r#"{"llvm-target": "thumbv7m-none-eabi", "arch": "arm",
+                         "max-atomic-width": 32, "os": "none"}"#);
+        return spec
+    }
+
+    #[test]
+    fn target_spec_overrides() {
+        let spec = target_spec("target-spec-overrides");
+        let env = env(&[("RUSTC_TARGET_SPEC_OVERRIDES",
+                         "max-atomic-width=0\nos=custom\nfeatures=+soft-float")]);
+        let cmd = configure(&args(&["--target", spec.to_str().unwrap()]), &env);
+        let patched = PathBuf::from(&cmd.args[1]);
+        assert!(patched != spec);
+        assert_eq!(patched.file_name(), spec.file_name());
+
+        let patched = Json::from_str(&read(&patched)).unwrap();
+        assert_eq!(patched.find("max-atomic-width").unwrap().as_u64(), Some(0));
+        assert_eq!(patched.find("os").unwrap().as_string(), Some("custom"));
+        assert_eq!(patched.find("features").unwrap().as_string(),
+                   Some("+soft-float"));
+        assert_eq!(patched.find("arch").unwrap().as_string(), Some("arm"));
+
+        // The joined form's rewritten in place.
+        let target = format!("--target={}", spec.display());
+        let cmd = configure(&args(&["--crate-name", "foo", &target]), &env);
+        let arg = cmd.args[2].to_str().unwrap();
+        assert!(arg.starts_with("--target="));
+        let joined = Path::new(&arg["--target=".len()..]);
+        assert!(joined != spec);
+        assert_eq!(joined.file_name(), spec.file_name());
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid value `\"lots\"` for target spec field \
+                               `max-atomic-width`, expected a number")]
+    fn target_spec_overrides_invalid() {
+        let spec = target_spec("target-spec-overrides-invalid");
+        let env = env(&[("RUSTC_TARGET_SPEC_OVERRIDES", "max-atomic-width=lots")]);
+        configure(&args(&["--target", spec.to_str().unwrap()]), &env);
+    }
+
+    #[test]
+    fn flags_audit() {
+        let dir = tmpdir("flags-audit");
+        let audit = dir.join("audit");
+        let env = env(&[("RUSTC_FLAGS_AUDIT", audit.to_str().unwrap()),
+                        ("RUSTC_CODEGEN_UNITS", "4")]);
+        for krate in ["core", "std"].iter() {
+            configure(&args(&["--crate-name", krate, "-C", "opt-level=3",
+                              "--target", "x86_64-unknown-linux-gnu"]), &env);
+        }
+        assert_eq!(read(&audit), "\
+# crate=core target=x86_64-unknown-linux-gnu stage=1
+--cfg
+stage1
+--sysroot
+sysroot
+-Cprefer-dynamic
+-C
+debug-assertions=n
+-C
+codegen-units=4
+
+# crate=std target=x86_64-unknown-linux-gnu stage=1
+--cfg
+stage1
+--sysroot
+sysroot
+-Cprefer-dynamic
+-C
+debug-assertions=n
+-C
+codegen-units=4
+
+");
+    }
+
+    #[test]
+    fn deny_warnings_crates() {
+        let env = env(&[("RUSTC_DENY_WARNINGS_CRATES", "std,core")]);
+        let cmd = configure(&args(&["--crate-name", "core",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(has_pair(&cmd, "-D", "warnings"));
+
+        let cmd = configure(&args(&["--crate-name", "libc",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        assert!(!has_pair(&cmd, "-D", "warnings"));
+    }
+
+    #[test]
+    fn thinlto_cache_dir() {
+        let dir = tmpdir("thinlto-cache-dir").join("cache");
+        let env = env(&[("RUSTC_THINLTO_CACHE_DIR", dir.to_str().unwrap())]);
+        let cmd = configure(&args(&["-C", "lto=thin",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env);
+        let arg = format!("link-args=-Wl,--thinlto-cache-dir={}", dir.display());
+        assert!(has_pair(&cmd, "-C", &arg));
+        assert!(dir.is_dir());
+    }
+
+    #[test]
+    #[should_panic(expected = "RUSTC_THINLTO_CACHE_DIR is set but ThinLTO isn't enabled")]
+    fn thinlto_cache_dir_without_thinlto() {
+        let dir = tmpdir("thinlto-cache-dir-without-thinlto");
+        let env = env(&[("RUSTC_THINLTO_CACHE_DIR", dir.to_str().unwrap())]);
+        configure(&args(&["-C", "lto=fat", "--target", "x86_64-unknown-linux-gnu"]),
+                  &env);
+    }
+
+    #[test]
+    #[should_panic(expected = "contains whitespace, which can't be passed through")]
+    fn thinlto_cache_dir_with_whitespace() {
+        let dir = tmpdir("thinlto-cache-dir-with-whitespace").join("thin lto");
+        let vars = env(&[("RUSTC_THINLTO_CACHE_DIR", dir.to_str().unwrap())]);
+        configure(&args(&["-C", "lto=thin", "--target", "x86_64-unknown-linux-gnu"]),
+                  &vars);
+    }
+
+    #[test]
+    fn full_dep_info() {
+        let cmd = configure(&args(&["--emit=link", "--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_FULL_DEP_INFO", "1")]));
+        assert!(has_pair(&cmd, "-Z", "binary-dep-depinfo"));
+        assert!(cmd.added.iter().any(|a| a == "--emit=dep-info"));
+        assert_eq!(cmd.args[0], "--emit=link");
+
+        let cmd = configure(&args(&["--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[]));
+        assert!(!has_pair(&cmd, "-Z", "binary-dep-depinfo"));
+        assert!(!cmd.added.iter().any(|a| a == "--emit=dep-info"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn ice_repro_dir() {
+        let dir = tmpdir("ice-repro-dir");
+        let repros = dir.join("repros");
+        let rustc = mock(&dir, "exit 101");
+        let vars = env(&[("RUSTC_REAL", &rustc),
+                         ("RUSTC_ICE_REPRO_DIR", repros.to_str().unwrap())]);
+        let cmd = configure(&args(&["--crate-name", "foo", "src/lib.rs",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &vars);
+        assert_eq!(run(&cmd), 101);
+
+        let entries = fs::read_dir(&repros).unwrap().collect::<Vec<_>>();
+        assert_eq!(entries.len(), 1);
+        let repro = entries[0].as_ref().unwrap().path();
+        let name = repro.file_name().unwrap().to_str().unwrap().to_string();
+        assert!(name.starts_with("foo-") && name.ends_with(".repro"));
+        let repro = read(&repro);
+        assert!(repro.starts_with(&format!("# compiler\n{}\n# args\n\
+                                            --crate-name\nfoo\nsrc/lib.rs\n\
+                                            --target\nx86_64-unknown-linux-gnu\n\
+                                            --cfg\nstage1\n", rustc)));
+        assert!(repro.contains("# env\n"));
+
+        mock(&dir, "exit 1");
+        let cmd = configure(&args(&["--crate-name", "bar",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &vars);
+        assert_eq!(run(&cmd), 1);
+        assert_eq!(fs::read_dir(&repros).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn debuginfo_crates() {
+        let vars = env(&[("RUSTC_DEBUGINFO_CRATES", "core,rustc_trans"),
+                         ("RUSTC_FLAGS", "-C debuginfo=0")]);
+        let krate = |name| {
+            configure(&args(&["--crate-name", name,
+                              "--target", "x86_64-unknown-linux-gnu"]), &vars)
+        };
+        assert_eq!(krate("core").codegen_opt("debuginfo"), Some("2".to_string()));
+        assert_eq!(krate("std").codegen_opt("debuginfo"), Some("0".to_string()));
+
+        let cmd = configure(&args(&["--crate-name", "rustc_trans",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &env(&[("RUSTC_DEBUGINFO_CRATES", "rustc_trans"),
+                                   ("RUSTC_DEBUGINFO_CRATES_LEVEL", "1")]));
+        assert_eq!(cmd.codegen_opt("debuginfo"), Some("1".to_string()));
+    }
+
+    #[cfg(unix)]
+    fn non_utf8(prefix: &str) -> OsString {
+        use std::os::unix::ffi::OsStringExt;
+        let mut bytes = prefix.as_bytes().to_vec();
+        bytes.push(0xff);
+        OsString::from_vec(bytes)
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn lenient_arg_encoding() {
+        let target = non_utf8("x86_64-unknown-linux-gnu");
+        let mut argv = args(&["--crate-name", "foo", "-C"]);
+        argv.push(non_utf8("incremental=/tmp/inc"));
+        argv.push(OsString::from("--target"));
+        argv.push(target.clone());
+        let cmd = configure(&argv, &env(&[]));
+
+        // It's still a target crate, and the target's passed on untouched.
+        assert_eq!(cmd.rustc, "rustc-real");
+        assert!(has_pair(&cmd, "--sysroot", "sysroot"));
+        assert_eq!(cmd.args[5], target);
+        assert_eq!(cmd.target, Some("x86_64-unknown-linux-gnu\u{fffd}".to_string()));
+        assert_eq!(cmd.codegen_opt("incremental"),
+                   Some("/tmp/inc\u{fffd}".to_string()));
+        assert_eq!(cmd.warnings, ["`-C` argument `incremental=/tmp/inc\u{fffd}` \
+                                   isn't valid UTF-8",
+                                  "`--target` argument `x86_64-unknown-linux-gnu\u{fffd}` \
+                                   isn't valid UTF-8"]);
+
+        // Options are still recognised with values that aren't valid UTF-8.
+        let cmd = configure(&argv, &env(&[("RUSTC_DISABLE_INCREMENTAL", "1")]));
+        assert_eq!(cmd.codegen_opt("incremental"), None);
+        assert_eq!(cmd.args[2], "--target");
+
+        let mut argv = args(&["--crate-name"]);
+        argv.push(non_utf8("foo"));
+        let cmd = configure(&argv, &env(&[]));
+        assert_eq!(cmd.crate_name(), Some("foo\u{fffd}"));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    #[should_panic(expected = "`--target` argument `x86_64-unknown-linux-gnu\u{fffd}` \
+                               isn't valid UTF-8")]
+    fn strict_arg_encoding() {
+        let mut argv = args(&["--crate-name", "foo", "--target"]);
+        argv.push(non_utf8("x86_64-unknown-linux-gnu"));
+        configure(&argv, &env(&[("RUSTC_STRICT_ARG_ENCODING", "1")]));
+    }
+
+    #[test]
+    fn sysroot_dep_override_bare_file() {
+        let vars = env(&[("RUSTC_SYSROOT_DEP_OVERRIDE", "libc=liblibc.rlib")]);
+        let cmd = configure(&args(&["--crate-name", "std",
+                                    "--target", "x86_64-unknown-linux-gnu"]),
+                            &vars);
+        assert!(has_pair(&cmd, "--extern", "libc=liblibc.rlib"));
+        assert!(has_pair(&cmd, "-L", "dependency=."));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reproducible_check_without_incremental() {
+        let dir = tmpdir("reproducible-rebuild");
+        let out = dir.join("out");
+        let calls = dir.join("calls");
+        fs::create_dir(&out).unwrap();
+        let rustc = mock(&dir, &format!("
+            echo \"$@\" >> {}
+            while [ $# -gt 0 ]; do
+                if [ \"$1\" = --out-dir ]; then out=$2; fi
+                shift
+            done
+            echo same > $out/libfoo.rlib
+        ", calls.display()));
+        let argv = args(&["--crate-name", "foo", "--crate-type", "lib",
+                          "--out-dir", out.to_str().unwrap(),
+                          "-C", "incremental=/tmp/incremental",
+                          "--target", "x86_64-unknown-linux-gnu"]);
+        let vars = env(&[("RUSTC_REAL", &rustc),
+                         ("RUSTC_REPRODUCIBLE_CHECK", "1")]);
+        assert_eq!(run(&configure(&argv, &vars)), 0);
+
+        let calls = read(&calls);
+        let calls = calls.lines().collect::<Vec<_>>();
+        assert_eq!(calls.len(), 2);
+        assert!(calls[0].contains("incremental="));
+        assert!(!calls[1].contains("incremental"));
+    }
 }